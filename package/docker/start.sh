@@ -1,5 +1,9 @@
 #!/usr/bin/env bash
 #
+# Maps the container environment variables to the client's SMDF_* settings.
+# SMDF_* variables may also be set directly, or a configuration file mounted and
+# passed in SMDF_CONFIG.
+#
 # Required environment variables:
 #   ENVIRONMENT
 #   TAGS
@@ -19,32 +23,16 @@ function ecs_task_id() {
     echo "${TASK_ID}"
 }
 
-OPTS=
-REGION=${REGION:-${AWS_REGION}}
-LOG_LEVEL=${LOG_LEVEL:-INFO}
-TASK_ID=$(ecs_task_id)
-NAME=${NAME:-${TASK_ID}}
-
-# Check that required environment variables are set.
-# https://stackoverflow.com/a/307735
-: "${NAME:?}"
-: "${ENVIRONMENT:?}"
-: "${TAGS:?}"
-
-echo "Region:  ${REGION}"
-echo "Log level:  ${LOG_LEVEL}"
-echo "Environment:  ${ENVIRONMENT}"
-echo "Name:  ${NAME}"
-echo "Tags:  ${TAGS}"
-
-if [[ -n $AUTO_DEREGISTER ]] ; then
-    echo "Auto-deregister:  ${AUTO_DEREGISTER}"
-    if [[ $AUTO_DEREGISTER = true ]] || [[ $AUTO_DEREGISTER = True ]]|| [[ $AUTO_DEREGISTER = 1 ]] ; then
-        OPTS="${OPTS} --auto-deregister"
-    fi
-fi
+export SMDF_REGION=${SMDF_REGION:-${REGION:-${AWS_REGION}}}
+export SMDF_LOG_LEVEL=${SMDF_LOG_LEVEL:-${LOG_LEVEL:-INFO}}
+export SMDF_NAME=${SMDF_NAME:-${NAME:-$(ecs_task_id)}}
+[[ -n $ENVIRONMENT ]] && export SMDF_ENVIRONMENT=${SMDF_ENVIRONMENT:-${ENVIRONMENT}}
+[[ -n $TAGS ]] && export SMDF_TAGS=${SMDF_TAGS:-${TAGS}}
+[[ -n $AUTO_DEREGISTER ]] && export SMDF_AUTO_DEREGISTER=${SMDF_AUTO_DEREGISTER:-${AUTO_DEREGISTER}}
+# The client runs unprivileged, so spools to its home directory unless a volume is mounted elsewhere.
+export SMDF_SPOOL_DIR=${SMDF_SPOOL_DIR:-${HOME}/spool}
 
-CMD="$1 ${OPTS} --region ${REGION} --log-level ${LOG_LEVEL} --environment ${ENVIRONMENT} --name ${NAME} --tags ${TAGS}"
-echo "Command:  ${CMD}"
+# Print the effective configuration, failing early if it is incomplete.
+"$1" config dump || exit 1
 
-exec ${CMD}
+exec "$1"
@@ -1,12 +1,13 @@
 Name:           smdf-client
 Summary:        SMDF client
 Version:        0.1.0
-Release:        1%{?dist}
+Release:        2%{?dist}
 License:        Apache-2.0 with Commons Clause
 Group:          Applications/System
 Source0:        %{name}.tar.gz
 #Source1:        <systemd-service>
-Requires:       coreutils openssl
+Requires:       openssl
+Requires(pre):  shadow-utils
 BuildRequires:  rust cargo openssl-devel
 BuildRoot:      %{_tmppath}/%{name}-%{version}-%{release}-root
 
@@ -27,14 +28,27 @@ cargo build --release --target-dir ./target
 %{__mkdir} -p %{buildroot}/%{_bindir}
 %{__mkdir} -p %{buildroot}/%{_unitdir}
 %{__mkdir} -p %{buildroot}/%{_sysconfdir}/sysconfig
+%{__mkdir} -p %{buildroot}/%{_sysconfdir}/smdf
+%{__mkdir} -p %{buildroot}/%{_localstatedir}/spool/%{name}
 %{__cp} ./target/release/%{name} %{buildroot}/%{_bindir}/%{name}
 %{__cp} ./package/el/%{name}.service %{buildroot}/%{_unitdir}/%{name}.service
 %{__cp} ./package/el/%{name}.sysconfig %{buildroot}/%{_sysconfdir}/sysconfig/%{name}
+%{__cp} ./package/el/client.toml %{buildroot}/%{_sysconfdir}/smdf/client.toml
+%{__cp} ./package/el/policy.toml %{buildroot}/%{_sysconfdir}/smdf/policy.toml
 
 %clean
 rm -rf %{buildroot}
 
+%pre
+getent group smdf > /dev/null || groupadd -r smdf
+getent passwd smdf > /dev/null || useradd -r -g smdf -d / -s /sbin/nologin -c "SMDF checks" smdf
+
 %post
+# The unit no longer passes the 0.1.0-1 sysconfig variables as flags,
+# so rename them to the SMDF_* variables the client reads instead.
+if [ $1 -gt 1 ] && [ -f %{_sysconfdir}/sysconfig/%{name} ]; then
+    sed -i -E 's/^(NAME|TAGS|ENVIRONMENT|REGION|LOG_LEVEL)=(.+)$/SMDF_\1=\2/' %{_sysconfdir}/sysconfig/%{name}
+fi
 systemctl daemon-reload
 
 %preun
@@ -47,7 +61,13 @@ systemctl daemon-reload
 %attr(755,root,root) %{_bindir}/%{name}
 %attr(644,root,root) %{_unitdir}/%{name}.service
 %attr(644,root,root) %config(noreplace) %{_sysconfdir}/sysconfig/%{name}
+%attr(644,root,root) %config(noreplace) %{_sysconfdir}/smdf/client.toml
+%attr(644,root,root) %config(noreplace) %{_sysconfdir}/smdf/policy.toml
+%dir %attr(700,root,root) %{_localstatedir}/spool/%{name}
 
 %changelog
+* Sun Oct 18 2026 agent <agent@local> - 0.1.0-2
+- Read the settings from /etc/smdf/client.toml, along with SMDF_* variables from /etc/sysconfig/smdf-client.
+- Rename NAME, TAGS, ENVIRONMENT, REGION and LOG_LEVEL in /etc/sysconfig/smdf-client to SMDF_* on upgrade.
 * Fri May 24 2019 Daniel Aharon <dan@danielaharon.com> - 0.1.0-1
 - Initial
@@ -0,0 +1,61 @@
+//! Construction of the AWS service clients.
+//! Every client uses the same credentials provider and its service's configured endpoint,
+//! and all share one HTTP client so connections are pooled and reused.
+//! Clients are cheap to clone and thread-safe, clones sharing the underlying connections.
+
+use std::error::Error;
+use std::sync::Arc;
+
+use rusoto_core::Region;
+use rusoto_core::request::HttpClient;
+use rusoto_lambda::LambdaClient;
+use rusoto_sqs::SqsClient;
+use rusoto_ssm::SsmClient;
+
+use crate::config::cli::Config;
+use crate::metrics::{self, RequestCountingDispatcher};
+use super::credentials::CredentialsProvider;
+
+
+/// The HTTP client shared by all the service clients.
+pub type Dispatcher = Arc<RequestCountingDispatcher<HttpClient>>;
+
+#[derive(Clone)]
+pub struct Clients {
+    dispatcher: Dispatcher,
+    credentials: CredentialsProvider,
+    ssm_region: Region,
+    lambda_region: Region,
+    sqs: SqsClient,
+}
+
+impl Clients {
+    pub fn new(config: &Config) -> Result<Self, Box<dyn Error>> {
+        let dispatcher = Arc::new(RequestCountingDispatcher::new(HttpClient::new()?));
+        let credentials = CredentialsProvider::new(&config.credentials, &config.region, &dispatcher)?;
+        metrics::service_client_created();
+        let sqs = SqsClient::new_with(dispatcher.clone(), credentials.clone(), config.sqs_region());
+        Ok(Self {
+            dispatcher,
+            credentials,
+            ssm_region: config.ssm_region(),
+            lambda_region: config.lambda_region(),
+            sqs,
+        })
+    }
+
+    pub fn ssm(&self) -> SsmClient {
+        metrics::service_client_created();
+        SsmClient::new_with(self.dispatcher.clone(), self.credentials.clone(), self.ssm_region.clone())
+    }
+
+    pub fn lambda(&self) -> LambdaClient {
+        metrics::service_client_created();
+        LambdaClient::new_with(self.dispatcher.clone(), self.credentials.clone(), self.lambda_region.clone())
+    }
+
+    /// The shared SQS client.
+    pub fn sqs(&self) -> SqsClient {
+        self.sqs.clone()
+    }
+}
@@ -0,0 +1,141 @@
+//! AWS credential providers.
+//! Without any credential settings the default chain is used: environment variables,
+//! the shared credentials file, then the container or instance role.
+//! A named profile or credentials file replaces the chain, and a role may be assumed on top
+//! of either, or with a web identity token.  Assumed role sessions are refreshed before they expire.
+
+use std::error::Error;
+use std::fs;
+use std::sync::Arc;
+
+use futures::Future;
+use rusoto_core::Region;
+use rusoto_core::credential::{
+    AutoRefreshingProvider, AwsCredentials, CredentialsError, DefaultCredentialsProvider,
+    ProfileProvider, ProvideAwsCredentials, StaticProvider,
+};
+use rusoto_sts::{
+    StsClient, StsAssumeRoleSessionCredentialsProvider, StsWebIdentityFederationSessionCredentialsProvider,
+};
+
+use crate::config::cli::Credentials;
+use super::clients::Dispatcher;
+
+
+/// Default name of assumed role sessions.
+const DEFAULT_SESSION_NAME: &str = "smdf-client";
+/// Profile read from a credentials file when none is named.
+const DEFAULT_PROFILE: &str = "default";
+
+pub type CredentialsFuture = Box<dyn Future<Item = AwsCredentials, Error = CredentialsError> + Send>;
+
+/// The configured credentials provider, shared by all the service clients.
+#[derive(Clone)]
+pub struct CredentialsProvider {
+    provider: Arc<Provider>,
+}
+
+enum Provider {
+    Default(DefaultCredentialsProvider),
+    Profile(ProfileProvider),
+    AssumeRole(AutoRefreshingProvider<StsAssumeRoleSessionCredentialsProvider>),
+    WebIdentity(AutoRefreshingProvider<WebIdentityProvider>),
+}
+
+impl CredentialsProvider {
+    /// Build the provider for the credential settings.
+    /// `region` and `dispatcher` are used for the STS requests made to assume a role.
+    pub fn new(credentials: &Credentials, region: &Region, dispatcher: &Dispatcher) -> Result<Self, Box<dyn Error>> {
+        let session_name = credentials.role_session_name.clone()
+            .unwrap_or_else(|| String::from(DEFAULT_SESSION_NAME));
+        let provider = match (&credentials.role_arn, &credentials.web_identity_token_file) {
+            (Some(role_arn), Some(token_file)) => {
+                // The token authenticates the request, so it is signed with blank credentials.
+                let sts_client = StsClient::new_with(
+                    dispatcher.clone(), StaticProvider::new_minimal(String::new(), String::new()), region.clone());
+                Provider::WebIdentity(AutoRefreshingProvider::new(WebIdentityProvider {
+                    sts_client,
+                    token_file: token_file.clone(),
+                    role_arn: role_arn.clone(),
+                    session_name,
+                })?)
+            },
+            (Some(role_arn), None) => {
+                let base = Self::base(credentials)?;
+                let sts_client = StsClient::new_with(dispatcher.clone(), base, region.clone());
+                Provider::AssumeRole(AutoRefreshingProvider::new(StsAssumeRoleSessionCredentialsProvider::new(
+                    sts_client,
+                    role_arn.clone(),
+                    session_name,
+                    credentials.external_id.clone(),
+                    None,
+                    None,
+                    None,
+                ))?)
+            },
+            (None, _) => return Self::base(credentials),
+        };
+        Ok(Self { provider: Arc::new(provider) })
+    }
+
+    /// The provider of the credentials used directly, or to assume a role.
+    fn base(credentials: &Credentials) -> Result<Self, Box<dyn Error>> {
+        let provider = match (&credentials.file, &credentials.profile) {
+            (Some(file), profile) => Provider::Profile(ProfileProvider::with_configuration(
+                file, profile.as_deref().unwrap_or(DEFAULT_PROFILE))),
+            (None, Some(profile)) => {
+                let mut provider = ProfileProvider::new()?;
+                provider.set_profile(profile.as_str());
+                Provider::Profile(provider)
+            },
+            (None, None) => Provider::Default(DefaultCredentialsProvider::new()?),
+        };
+        Ok(Self { provider: Arc::new(provider) })
+    }
+}
+
+impl ProvideAwsCredentials for CredentialsProvider {
+    type Future = CredentialsFuture;
+
+    fn credentials(&self) -> Self::Future {
+        match *self.provider {
+            Provider::Default(ref provider) => Box::new(provider.credentials()),
+            Provider::Profile(ref provider) => Box::new(provider.credentials()),
+            Provider::AssumeRole(ref provider) => Box::new(provider.credentials()),
+            Provider::WebIdentity(ref provider) => Box::new(provider.credentials()),
+        }
+    }
+}
+
+/// Assumes a role with the web identity token from the file.
+/// The token is re-read on every refresh as it is rotated by the platform, eg. EKS.
+struct WebIdentityProvider {
+    sts_client: StsClient,
+    token_file: String,
+    role_arn: String,
+    session_name: String,
+}
+
+impl ProvideAwsCredentials for WebIdentityProvider {
+    type Future = CredentialsFuture;
+
+    fn credentials(&self) -> Self::Future {
+        let token = match fs::read_to_string(&self.token_file) {
+            Ok(token) => token.trim().to_string(),
+            Err(e) => {
+                let message = format!("Failed to read web identity token file {}:  {}", self.token_file, e);
+                return Box::new(futures::future::err(CredentialsError::new(message)));
+            },
+        };
+        let provider = StsWebIdentityFederationSessionCredentialsProvider::new(
+            self.sts_client.clone(),
+            token,
+            None,
+            self.role_arn.clone(),
+            self.session_name.clone(),
+            None,
+            None,
+        );
+        Box::new(provider.credentials())
+    }
+}
@@ -1,6 +1,5 @@
 use std::error::Error;
 
-use rusoto_core::Region;
 use rusoto_lambda::{InvocationRequest, LambdaClient, Lambda};
 use serde::Serialize;
 use serde::de::DeserializeOwned;
@@ -24,7 +23,7 @@ impl Error for RegistrationError { }
 pub trait RegistrationRequest: Serialize {
     type Response: DeserializeOwned;
 
-    fn execute(&self, region: &Region, function: &str) -> Result<Self::Response, Box<dyn Error>> {
+    fn execute(&self, client: &LambdaClient, function: &str) -> Result<Self::Response, Box<dyn Error>> {
         let payload = serde_json::to_string(self).unwrap().as_bytes().to_vec();
         let invoke_request = InvocationRequest {
             client_context: None,
@@ -34,7 +33,6 @@ pub trait RegistrationRequest: Serialize {
             payload: Some(payload),
             qualifier: None
         };
-        let client = LambdaClient::new(region.clone());
         let response = client.invoke(invoke_request).sync()?;
         if response.status_code.unwrap() == 200 {
             let registration_response = serde_json::from_slice::<Self::Response>(response.payload.unwrap().as_ref())?;
@@ -49,5 +47,7 @@ pub trait RegistrationRequest: Serialize {
     }
 }
 
+pub mod clients;
+pub mod credentials;
 pub mod registration;
 pub mod deregistration;
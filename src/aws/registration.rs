@@ -1,8 +1,18 @@
+use clap::crate_version;
+
 /// Client registration.
+/// Repeated periodically as a heartbeat, reporting the client's status.
 #[derive(Debug, Serialize)]
 pub struct Request {
     pub name: String,
     pub tags: Vec<String>,
+    pub version: String,
+    /// Seconds since the client started.
+    #[serde(rename = "uptimeSeconds")]
+    pub uptime_seconds: u64,
+    /// The number of checks running.
+    #[serde(rename = "inFlight")]
+    pub in_flight: usize,
 }
 
 impl super::RegistrationRequest for Request {
@@ -10,12 +20,15 @@ impl super::RegistrationRequest for Request {
 }
 
 impl Request {
-    pub fn new<T>(name: &str, tags: &[T]) -> Self
+    pub fn new<T>(name: &str, tags: &[T], uptime_seconds: u64, in_flight: usize) -> Self
         where T: AsRef<str>
     {
         Self {
             name: String::from(name),
             tags: tags.iter().map(|s| { String::from(s.as_ref()) }).collect(),
+            version: String::from(crate_version!()),
+            uptime_seconds,
+            in_flight,
         }
     }
 }
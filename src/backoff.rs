@@ -0,0 +1,83 @@
+//! Capped exponential backoff with full jitter.
+//! Each delay is drawn uniformly between zero and the exponentially growing ceiling,
+//! so that clients failing at the same time spread their retries out rather than retrying in lockstep.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    /// The number of consecutive failures.
+    attempts: u32,
+}
+
+impl Backoff {
+    /// Back off from `base`, doubling the ceiling with every failure up to `max`.
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self { base, max, attempts: 0 }
+    }
+
+    /// Record a failure and return how long to wait before retrying.
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self.ceiling();
+        self.attempts = self.attempts.saturating_add(1);
+        let millis = ceiling.as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(0, millis + 1))
+    }
+
+    /// Record a success, starting again from `base` on the next failure.
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+
+    /// The number of consecutive failures.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// The longest the next delay can be.
+    fn ceiling(&self) -> Duration {
+        // Doubling more than 31 times overflows, and is well past any sensible maximum.
+        self.base.checked_mul(1 << self.attempts.min(31))
+            .map_or(self.max, |ceiling| ceiling.min(self.max))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ceiling_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        let ceilings: Vec<u64> = (0..6)
+            .map(|_| {
+                let ceiling = backoff.ceiling();
+                assert!(backoff.next_delay() <= ceiling);
+                ceiling.as_secs()
+            })
+            .collect();
+        assert_eq!(vec![1, 2, 4, 8, 10, 10], ceilings);
+        assert_eq!(6, backoff.attempts());
+
+        for _ in 0..100 {
+            backoff.next_delay();
+        }
+        assert_eq!(Duration::from_secs(10), backoff.ceiling());
+    }
+
+    #[test]
+    fn reset_starts_from_base() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        for _ in 0..5 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+        assert_eq!(0, backoff.attempts());
+        assert_eq!(Duration::from_secs(1), backoff.ceiling());
+    }
+}
@@ -0,0 +1,349 @@
+//! Coalesce the per-check queue calls into batch requests.
+//! Entries are collected on a background thread for a short flush window,
+//! or until a full batch is available, and then sent together.
+//! Failed entries are retried after a backoff, collecting new entries meanwhile.
+
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, warn};
+
+use crate::backoff::Backoff;
+use crate::deadline::Worker;
+
+
+/// The maximum number of entries SQS accepts in a single batch request.
+pub const MAX_BATCH_SIZE: usize = 10;
+/// The maximum total payload of a `SendMessageBatch` request.
+pub const MAX_BATCH_PAYLOAD: usize = 262_144;
+/// How long to wait for more entries before sending a partial batch.
+pub const FLUSH_WINDOW: Duration = Duration::from_millis(200);
+/// How many times an entry is attempted before being dropped.
+const MAX_ATTEMPTS: u32 = 3;
+/// The ceiling of the delay before the first retry, doubling with each consecutive failed flush.
+const RETRY_BACKOFF_BASE: Duration = Duration::from_millis(500);
+/// The longest delay before retrying.
+const RETRY_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+pub struct Batcher<T> {
+    name: String,
+    sender: Mutex<Option<mpsc::Sender<T>>>,
+    worker: Mutex<Option<Worker>>,
+    /// Once closing, when to stop sending and hand the pending entries to the fallback.
+    deadline: Arc<Mutex<Option<Instant>>>,
+}
+
+impl<T: Send + 'static> Batcher<T> {
+    /// Start the batching thread.
+    /// `flush` is called with up to [MAX_BATCH_SIZE] entries and returns the
+    /// indexes of the entries which failed with a retryable error.
+    /// Entries still failing after [MAX_ATTEMPTS] are dropped.
+    pub fn new<F>(name: &str, flush: F) -> Self
+        where F: FnMut(&[T]) -> Vec<usize> + Send + 'static
+    {
+        Self::with_fallback(name, flush, |_| {})
+    }
+
+    /// Start the batching thread, handing the entries still failing after [MAX_ATTEMPTS] to `fallback`.
+    pub fn with_fallback<F, G>(name: &str, flush: F, fallback: G) -> Self
+        where F: FnMut(&[T]) -> Vec<usize> + Send + 'static,
+              G: FnMut(T) + Send + 'static
+    {
+        let (sender, receiver) = mpsc::channel::<T>();
+        let thread_name = name.to_string();
+        let deadline = Arc::new(Mutex::new(None));
+        let c_deadline = deadline.clone();
+        let worker = Worker::spawn(&format!("batch-{}", name),
+                                   move || batch_loop(&thread_name, &receiver, &c_deadline, flush, fallback))
+            .expect("Failed to spawn batching thread.");
+        Self {
+            name: name.to_string(),
+            sender: Mutex::new(Some(sender)),
+            worker: Mutex::new(Some(worker)),
+            deadline,
+        }
+    }
+
+    /// Queue an entry for the next batch.
+    pub fn push(&self, entry: T) {
+        let sent = match self.sender.lock().unwrap().as_ref() {
+            Some(sender) => sender.send(entry).is_ok(),
+            None => false,
+        };
+        if !sent {
+            error!("The {} batcher is closed, dropping entry.", self.name);
+        }
+    }
+
+    /// Flush the pending entries and stop the batching thread.
+    pub fn close(&self) {
+        self.sender.lock().unwrap().take();
+        if let Some(worker) = self.worker.lock().unwrap().take() {
+            worker.join();
+        }
+    }
+
+    /// Flush the pending entries and stop the batching thread, waiting until the deadline at most.
+    /// Entries still pending at the deadline are handed to the fallback rather than sent.
+    /// Returns whether the thread finished in time.
+    pub fn close_by(&self, deadline: Instant) -> bool {
+        *self.deadline.lock().unwrap() = Some(deadline);
+        self.sender.lock().unwrap().take();
+        match self.worker.lock().unwrap().take() {
+            Some(worker) => worker.join_by(deadline),
+            None => true,
+        }
+    }
+}
+
+fn batch_loop<T, F, G>(name: &str, receiver: &mpsc::Receiver<T>, deadline: &Mutex<Option<Instant>>,
+                       mut flush: F, mut fallback: G)
+    where F: FnMut(&[T]) -> Vec<usize>, G: FnMut(T)
+{
+    let mut pending = Pending::default();
+    let mut closed = false;
+    while !closed {
+        if past(deadline) {
+            // Closing, and out of time to wait for the channel to disconnect.
+            receiver.try_iter().for_each(|entry| pending.push(entry));
+            break;
+        }
+        if pending.entries.is_empty() {
+            match receiver.recv() {
+                Ok(entry) => pending.push(entry),
+                Err(_) => break,
+            }
+        }
+        if !pending.wait_for_retry(receiver, deadline) {
+            continue;
+        }
+        let flush_at = Instant::now() + FLUSH_WINDOW;
+        while pending.entries.len() < MAX_BATCH_SIZE {
+            let now = Instant::now();
+            if now >= flush_at {
+                break;
+            }
+            match receiver.recv_timeout(flush_at - now) {
+                Ok(entry) => pending.push(entry),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    closed = true;
+                    break;
+                },
+            }
+        }
+        pending.flush(name, &mut flush, &mut fallback);
+    }
+    // Closed, send whatever is left.
+    while !pending.entries.is_empty() {
+        if past(deadline) {
+            warn!("Close deadline reached with {} {} entries unsent.", pending.entries.len(), name);
+            pending.entries.drain(..).for_each(&mut fallback);
+            break;
+        }
+        if pending.wait_for_retry(receiver, deadline) {
+            pending.flush(name, &mut flush, &mut fallback);
+        }
+    }
+}
+
+/// Whether the batcher is closing and past its deadline.
+fn past(deadline: &Mutex<Option<Instant>>) -> bool {
+    deadline.lock().unwrap().is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Entries waiting to be sent, along with the number of times each has been attempted.
+struct Pending<T> {
+    entries: Vec<T>,
+    attempts: Vec<u32>,
+    backoff: Backoff,
+    /// When failed entries may be retried.
+    retry_at: Option<Instant>,
+}
+
+impl<T> Default for Pending<T> {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            attempts: Vec::new(),
+            backoff: Backoff::new(RETRY_BACKOFF_BASE, RETRY_BACKOFF_MAX),
+            retry_at: None,
+        }
+    }
+}
+
+impl<T> Pending<T> {
+    fn push(&mut self, entry: T) {
+        self.entries.push(entry);
+        self.attempts.push(0);
+    }
+
+    /// Wait until failed entries may be retried, collecting new entries meanwhile.
+    /// Returns `false` if the batcher is closing and past its deadline.
+    fn wait_for_retry(&mut self, receiver: &mpsc::Receiver<T>, deadline: &Mutex<Option<Instant>>) -> bool {
+        while let Some(retry_at) = self.retry_at {
+            if past(deadline) {
+                return false;
+            }
+            let now = Instant::now();
+            if now >= retry_at {
+                break;
+            }
+            // Waiting no longer than the flush window at a time, so that a close deadline is noticed.
+            let wait = (retry_at - now).min(FLUSH_WINDOW);
+            match receiver.recv_timeout(wait) {
+                Ok(entry) => self.push(entry),
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => thread::sleep(wait),
+            }
+        }
+        self.retry_at = None;
+        true
+    }
+
+    /// Flush up to [MAX_BATCH_SIZE] of the pending entries,
+    /// putting the retryable failures back at the front of the queue to be retried after a backoff.
+    fn flush<F, G>(&mut self, name: &str, flush: &mut F, fallback: &mut G)
+        where F: FnMut(&[T]) -> Vec<usize>, G: FnMut(T)
+    {
+        let count = self.entries.len().min(MAX_BATCH_SIZE);
+        let batch: Vec<T> = self.entries.drain(..count).collect();
+        let attempts: Vec<u32> = self.attempts.drain(..count).collect();
+        let failed = flush(&batch);
+        debug!("Flushed {} {} entries, {} failed.", count, name, failed.len());
+
+        let mut retry_entries = Vec::new();
+        let mut retry_attempts = Vec::new();
+        for (index, (entry, attempt)) in batch.into_iter().zip(attempts).enumerate() {
+            if !failed.contains(&index) {
+                continue;
+            }
+            if attempt + 1 < MAX_ATTEMPTS {
+                retry_entries.push(entry);
+                retry_attempts.push(attempt + 1);
+            } else {
+                error!("Giving up on {} entry after {} failed attempts.", name, MAX_ATTEMPTS);
+                fallback(entry);
+            }
+        }
+        if failed.is_empty() {
+            self.backoff.reset();
+        } else if !retry_entries.is_empty() {
+            let delay = self.backoff.next_delay();
+            debug!("Retrying {} {} entries in {} ms.", retry_entries.len(), name, delay.as_millis());
+            self.retry_at = Some(Instant::now() + delay);
+        }
+        self.entries.splice(0..0, retry_entries);
+        self.attempts.splice(0..0, retry_attempts);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn batches_entries() {
+        let batches: Arc<Mutex<Vec<Vec<usize>>>> = Arc::new(Mutex::new(Vec::new()));
+        let c_batches = batches.clone();
+        let batcher = Batcher::new("test", move |entries: &[usize]| {
+            c_batches.lock().unwrap().push(entries.to_vec());
+            vec![]
+        });
+        for i in 0..25 {
+            batcher.push(i);
+        }
+        batcher.close();
+
+        let batches = batches.lock().unwrap();
+        assert!(batches.iter().all(|b| b.len() <= MAX_BATCH_SIZE));
+        let flushed: Vec<usize> = batches.iter().flatten().cloned().collect();
+        assert_eq!((0..25).collect::<Vec<usize>>(), flushed);
+    }
+
+    #[test]
+    fn retries_failed_entries() {
+        let attempts: Arc<Mutex<Vec<usize>>> = Arc::new(Mutex::new(Vec::new()));
+        let c_attempts = attempts.clone();
+        let batcher = Batcher::new("test", move |entries: &[&str]| {
+            let mut attempts = c_attempts.lock().unwrap();
+            entries.iter()
+                .enumerate()
+                .filter(|(_, entry)| **entry == "fail")
+                .map(|(index, _)| {
+                    attempts.push(index);
+                    index
+                })
+                .collect()
+        });
+        batcher.push("ok");
+        batcher.push("fail");
+        batcher.close();
+
+        assert_eq!(MAX_ATTEMPTS as usize, attempts.lock().unwrap().len());
+    }
+
+    #[test]
+    fn backs_off_between_retries() {
+        let mut pending = Pending::default();
+        pending.push("fail");
+        pending.flush("test", &mut |entries: &[&str]| (0..entries.len()).collect(), &mut |_| {});
+        assert_eq!(vec!["fail"], pending.entries);
+        assert!(pending.retry_at.is_some());
+        assert_eq!(1, pending.backoff.attempts());
+
+        let (_sender, receiver) = mpsc::channel();
+        assert!(pending.wait_for_retry(&receiver, &Mutex::new(None)));
+        assert!(pending.retry_at.is_none());
+        pending.flush("test", &mut |_: &[&str]| vec![], &mut |_| {});
+        assert!(pending.entries.is_empty());
+        assert!(pending.retry_at.is_none());
+        assert_eq!(0, pending.backoff.attempts());
+    }
+
+    #[test]
+    fn falls_back_after_close_deadline() {
+        let fallen_back: Arc<Mutex<Vec<usize>>> = Arc::new(Mutex::new(Vec::new()));
+        let c_fallen_back = fallen_back.clone();
+        let batcher = Batcher::with_fallback(
+            "test",
+            |entries: &[usize]| {
+                std::thread::sleep(Duration::from_millis(300));
+                (0..entries.len()).collect()
+            },
+            move |entry| c_fallen_back.lock().unwrap().push(entry));
+        for i in 0..20 {
+            batcher.push(i);
+        }
+        // Only waits until the deadline, even though a flush is in progress.
+        let started_at = Instant::now();
+        batcher.close_by(Instant::now() + Duration::from_millis(500));
+        assert!(started_at.elapsed() < Duration::from_secs(1));
+        // Once the flush in progress ends, the rest are handed to the fallback without being sent.
+        std::thread::sleep(Duration::from_millis(500));
+        assert_eq!(20, fallen_back.lock().unwrap().len());
+    }
+
+    #[test]
+    fn falls_back_after_max_attempts() {
+        let fallen_back: Arc<Mutex<Vec<&str>>> = Arc::new(Mutex::new(Vec::new()));
+        let c_fallen_back = fallen_back.clone();
+        let batcher = Batcher::with_fallback(
+            "test",
+            |entries: &[&str]| entries.iter()
+                .enumerate()
+                .filter(|(_, entry)| **entry == "fail")
+                .map(|(index, _)| index)
+                .collect(),
+            move |entry| c_fallen_back.lock().unwrap().push(entry));
+        batcher.push("ok");
+        batcher.push("fail");
+        batcher.close();
+
+        assert_eq!(vec!["fail"], *fallen_back.lock().unwrap());
+    }
+}
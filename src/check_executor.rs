@@ -1,161 +1,388 @@
+use std::io;
+use std::os::unix::process::ExitStatusExt;
 use std::process;
+use std::sync::Arc;
+use std::time::Duration;
 
-use chrono::Utc;
-use log::{debug, error};
-use rusoto_sqs::{
-    SqsClient, Sqs,
-    Message,
-    DeleteMessageRequest, SendMessageRequest,
-};
+use chrono::{DateTime, Utc};
+use futures::Future;
+use futures::future::{self, Either};
+use log::{debug, error, info, warn};
 
+use crate::batch::Batcher;
 use crate::config::cli::Config;
+use crate::in_flight::InFlight;
 use crate::messages::check::{
-    ClientCheckMessage, ClientCheckResultMessage, CheckResultStatus
+    self, ClientCheckMessage, ClientCheckResultMessage, CheckResultStatus, OutputCapture
 };
-use crate::timeout;
+use crate::messages::perfdata::{self, Metric};
+use crate::metrics;
+use crate::policy::{self, Policy};
+use crate::privileges::{Identities, Identity};
+use crate::signature::{self, Rejected, Verifier};
+use crate::tags;
+use crate::timeout::{self, Output};
+use crate::transport::ReceivedMessage;
+
 
+/// Script which runs the check command, given as `$1`, with `stderr` redirected to `stdout`,
+/// keeping the order the command wrote them in.
+const MERGED_OUTPUT_SCRIPT: &str = "exec 2>&1; exec /bin/sh -c \"$1\"";
+/// Script which runs the command given by its arguments with `stderr` redirected to `stdout`.
+const MERGED_OUTPUT_EXEC_SCRIPT: &str = "exec 2>&1; exec \"$@\"";
+
+/// The result of a check, along with the receipt handle of its command message
+/// which is to be deleted once the result has been sent or spooled.
+#[derive(Clone, Debug)]
+pub struct CompletedCheck {
+    pub result: ClientCheckResultMessage,
+    pub receipt_handle: String,
+}
 
 pub struct CheckExecutor {
     pub config: Config,
-    pub command_queue: String,
-    pub result_queue: String,
-    pub message: Message,
+    pub message: ReceivedMessage,
+    pub in_flight: Arc<InFlight>,
+    pub results: Arc<Batcher<CompletedCheck>>,
+    /// Receipt handles of messages to delete without a result.
+    pub deletions: Arc<Batcher<String>>,
+    /// The commands checks may run, or any without a policy.
+    pub policy: Option<Arc<Policy>>,
+    /// Verifies the signatures of messages, which are not required without it.
+    pub verifier: Option<Arc<Verifier>>,
+    /// The identities checks run as.
+    pub identities: Arc<Identities>,
 }
 
 impl CheckExecutor {
-    pub fn new(config: Config, command_queue: String, result_queue: String, message: Message) -> Self {
-        Self {
-            config,
-            command_queue,
-            result_queue,
-            message,
+    /// Run the check, queueing its result to be published once complete.
+    /// The returned future is to be spawned on the runtime.
+    pub fn execute(self) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let message_id = self.message.message_id.clone();
+        let receipt_handle = self.message.receipt_handle.clone();
+        if !self.in_flight.contains(&receipt_handle) {
+            debug!("Skipping aborted message {}", message_id);
+            return Box::new(future::ok(()));
+        }
+        let check_message = match parse_client_check_message(&self.message, self.verifier.as_deref()) {
+            Ok(check_message) => check_message,
+            Err(rejected) => {
+                match rejected {
+                    Rejected::OutsideWindow(ref check_message, _) => {
+                        // Validly signed, so the result can say why it did not run.
+                        metrics::message_replayed();
+                        warn!(target: policy::AUDIT_TARGET, "Rejecting check {}/{}:  {}",
+                              check_message.group, check_message.name, rejected);
+                        self.skip(check_message, Some(format!("Check rejected by client:  {}", rejected)));
+                    },
+                    _ => {
+                        // The message can't be trusted to say where a result would go, or was already run,
+                        // so it is only deleted.
+                        if let Rejected::Replayed = rejected {
+                            metrics::message_replayed();
+                        } else {
+                            metrics::message_discarded();
+                        }
+                        warn!(target: policy::AUDIT_TARGET, "Discarding message {}:  {}", message_id, rejected);
+                        self.discard();
+                    },
+                }
+                return Box::new(future::ok(()));
+            },
+        };
+        // Checks misrouted to this client are never run.
+        if let Err(reason) = tags::accept(&self.config.tags, &self.config.exclude_tags, &check_message.tags) {
+            metrics::check_rejected();
+            warn!("Rejecting check {}/{}:  {}", check_message.group, check_message.name, reason);
+            self.skip(&check_message, Some(format!("Check rejected by client:  {}", reason)));
+            return Box::new(future::ok(()));
         }
+        if let Some(age) = expired(&check_message, self.config.max_check_age, Utc::now()) {
+            metrics::check_expired();
+            warn!("Skipping check {}/{} scheduled {} seconds ago.", check_message.group, check_message.name, age);
+            let reason = if self.config.report_expired {
+                Some(format!("Check expired without running, scheduled {} seconds ago.", age))
+            } else {
+                None
+            };
+            self.skip(&check_message, reason);
+            return Box::new(future::ok(()));
+        }
+        let identity = match self.identities.for_check(&check_message.run_as) {
+            Ok(identity) => identity,
+            Err(reason) => {
+                metrics::user_denied();
+                warn!(target: policy::AUDIT_TARGET, "Denied the user or group of check {}/{}:  {}",
+                      check_message.group, check_message.name, reason);
+                self.skip(&check_message, Some(format!("Check denied by client:  {}", reason)));
+                return Box::new(future::ok(()));
+            },
+        };
+        self.in_flight.set_started(
+            &receipt_handle, Duration::from_secs(check_message.timeout as u64) + timeout::KILL_GRACE_PERIOD);
+        let c_in_flight = self.in_flight.clone();
+        let c_receipt_handle = receipt_handle.clone();
+        let result = execute_command(&check_message, &self.config.client_name, self.config.max_output,
+                                     self.policy.as_deref(), identity.as_ref(),
+                                     move |pid| c_in_flight.set_pid(&c_receipt_handle, pid));
+        Box::new(result.map(move |result_msg| {
+            debug!("Result message:  {:?}", result_msg);
+            if !self.in_flight.complete(&receipt_handle) {
+                info!("Check was aborted, leaving message {} on the queue.", message_id);
+                return;
+            }
+            self.results.push(CompletedCheck { result: result_msg, receipt_handle });
+        }))
     }
+}
 
-    pub fn execute(&self) {
-        let check_message = parse_client_check_message(&self.message).unwrap();
-        let result_msg = execute_command(&check_message, &self.config.client_name);
-        debug!("Result message:  {:?}", result_msg);
-        let sqs_client = SqsClient::new(self.config.region.clone());
-        if let Ok(result_msg) = result_msg {
-            send_result(&sqs_client, &self.result_queue, result_msg);
+impl CheckExecutor {
+    /// Finish the check without running it, sending an `UNKNOWN` result with the reason if given,
+    /// or else only deleting its message.
+    fn skip(self, check: &ClientCheckMessage, reason: Option<String>) {
+        let reason = match reason {
+            Some(reason) => reason,
+            None => return self.discard(),
+        };
+        if self.in_flight.complete(&self.message.receipt_handle) {
+            let result = ClientCheckResultMessage::new(
+                check, &self.config.client_name, Utc::now(), CheckResultStatus::UNKNOWN, reason);
+            self.results.push(CompletedCheck { result, receipt_handle: self.message.receipt_handle });
         }
-        delete_message(&sqs_client, &self.command_queue, &self.message);
+    }
+
+    /// Finish with the message without a result, only deleting it.
+    fn discard(self) {
+        if self.in_flight.complete(&self.message.receipt_handle) {
+            self.deletions.push(self.message.receipt_handle);
+        }
+    }
+}
+
+/// How many seconds ago the check was scheduled, if longer ago than its maximum age.
+/// The check's own maximum age overrides `default_max_age`, and a maximum age of 0 never expires.
+fn expired(check: &ClientCheckMessage, default_max_age: u64, now: DateTime<Utc>) -> Option<u64> {
+    let max_age = check.max_age.unwrap_or(default_max_age);
+    let age = now.signed_duration_since(check.scheduled_at).num_seconds();
+    if max_age > 0 && age > max_age as i64 {
+        Some(age as u64)
+    } else {
+        None
     }
 }
 
-/// Parse the SQS message into [ClientCheckMessage] struct.
-fn parse_client_check_message(message: &Message)
-                              -> Result<ClientCheckMessage, Box<dyn std::error::Error>>
+/// Parse the command message into [ClientCheckMessage] struct.
+/// With a verifier, the message must be signed by one of its keys, see [crate::signature].
+fn parse_client_check_message(message: &ReceivedMessage, verifier: Option<&Verifier>)
+                              -> Result<ClientCheckMessage, Rejected>
 {
-    debug!("Received the following message:  {:?}", message.body.as_ref().unwrap());
-    let check = serde_json::from_str::<ClientCheckMessage>(message.body.as_ref().unwrap())?;
+    debug!("Received the following message:  {:?}", message.body);
+    let check = match verifier {
+        Some(verifier) => verifier.verify(&message.body, Utc::now())?,
+        None => signature::unverified(&message.body)?,
+    };
     debug!("Parsed JSON message:  {:?}", check);
     Ok(check)
 }
 
 /// Execute the command as specified by the check.
-fn execute_command(check: &ClientCheckMessage, client_name: &str)
-                   -> Result<ClientCheckResultMessage, Box<dyn std::error::Error>>
+/// With a policy, the command is only run if the policy allows it, and is run directly rather than by the shell.
+/// With an identity, the command runs as its user and groups rather than the client's.
+/// Output beyond `max_output` bytes per stream is discarded.
+/// `on_spawn` is called with the process group ID of the command once it has been started.
+/// Failures to run the command are reported in the result, so the future always succeeds.
+fn execute_command<F>(check: &ClientCheckMessage, client_name: &str, max_output: usize, policy: Option<&Policy>,
+                      identity: Option<&Identity>, on_spawn: F) -> impl Future<Item = ClientCheckResultMessage, Error = ()>
+    where F: FnOnce(u32) + Send + 'static
 {
     let executed_at = Utc::now();
+    let mut command = match policy {
+        None => shell_command(check),
+        Some(policy) => match policy.evaluate(&check.command) {
+            Ok(words) => {
+                debug!(target: policy::AUDIT_TARGET, "Allowed check {}/{} to run {:?}", check.group, check.name, words);
+                direct_command(check, &words)
+            },
+            Err(reason) => {
+                metrics::check_denied();
+                warn!(target: policy::AUDIT_TARGET, "Denied check {}/{} running `{}`:  {}",
+                      check.group, check.name, check.command, reason);
+                return Either::A(future::ok(ClientCheckResultMessage::new(
+                    check, client_name, executed_at, CheckResultStatus::UNKNOWN,
+                    format!("Check denied by client policy:  {}", reason))));
+            },
+        },
+    };
     debug!("Running check:  {}", check.command);
-    let output = process::Command::new(timeout::CMD)
-        .args(timeout::opts(check.timeout))
-        .args(&["/bin/sh", "-c", &check.command])
-        .env_clear()
-        .output();
-
-    let result_msg: ClientCheckResultMessage = match output {
-        Ok(opt) => {
-            let output_msg: String = if opt.status.code().unwrap() == timeout::EXIT_CODE {
-                error!("Command exited with status code {}, signifying a time-out:  {}",
-                       timeout::EXIT_CODE, check.command);
-                let err_msg: String = if opt.stderr.is_empty() { String::from("<empty>") }
-                    else { String::from_utf8_lossy(&opt.stderr).to_string() };
-                error!("{}", err_msg);
-                format!("Check command timed out after {} seconds:  {}",
-                    Utc::now().signed_duration_since(executed_at).num_seconds(),
-                    err_msg)
-            } else {
-                String::from_utf8_lossy(&opt.stdout).to_string()
-            };
-            ClientCheckResultMessage {
-                completed_at: Utc::now(),
-                scheduled_at: check.scheduled_at,
-                executed_at,
-                group: check.group.clone(),
-                name: check.name.clone(),
-                source: String::from(client_name),
-                status: CheckResultStatus::from_exit_code(opt.status.code().unwrap()),
-                output: output_msg,
-            }
+    command.env_clear();
+    if let Some(identity) = identity {
+        debug!(target: policy::AUDIT_TARGET, "Running check {}/{} as {} ({}:{})",
+               check.group, check.name, identity.user, identity.uid, identity.gid);
+        identity.apply(&mut command);
+    }
+    let check = check.clone();
+    let client_name = client_name.to_string();
+    Either::B(timeout::run(command, Duration::from_secs(check.timeout as u64), max_output, on_spawn)
+        .then(move |output| Ok(result_message(&check, &client_name, executed_at, output))))
+}
+
+/// The check's command, run by the shell.
+fn shell_command(check: &ClientCheckMessage) -> process::Command {
+    let mut command = process::Command::new("/bin/sh");
+    if check.capture == OutputCapture::Merged {
+        command.args(["-c", MERGED_OUTPUT_SCRIPT, "sh", &check.command]);
+    } else {
+        command.args(["-c", &check.command]);
+    }
+    command
+}
+
+/// The check's command, given as its words, run directly.
+fn direct_command(check: &ClientCheckMessage, words: &[String]) -> process::Command {
+    if check.capture == OutputCapture::Merged {
+        let mut command = process::Command::new("/bin/sh");
+        command.args(["-c", MERGED_OUTPUT_EXEC_SCRIPT, "sh"]).args(words);
+        command
+    } else {
+        let mut command = process::Command::new(&words[0]);
+        command.args(&words[1..]);
+        command
+    }
+}
+
+/// Build the check's result from the output of its command.
+fn result_message(check: &ClientCheckMessage, client_name: &str, executed_at: DateTime<Utc>,
+                  output: io::Result<Output>) -> ClientCheckResultMessage
+{
+    let stderr = match output {
+        Ok(ref opt) if check.capture == OutputCapture::Separate =>
+            Some((captured(&opt.stderr, opt.stderr_bytes), opt.stderr_bytes)),
+        _ => None,
+    };
+    let mut metrics: Vec<Metric> = Vec::new();
+
+    // The output, along with its size before truncation, prefixed with a description when the check
+    // did not exit normally.  `stdout` holds the merged streams, or only stdout with `stderr` kept apart.
+    let described = |description: String, opt: &Output| {
+        let (output, output_bytes) = if opt.stdout.is_empty() { (String::from("<empty>"), "<empty>".len()) }
+            else { (captured(&opt.stdout, opt.stdout_bytes), opt.stdout_bytes) };
+        (format!("{}{}", description, output), description.len() + output_bytes)
+    };
+    let (status, (output_msg, output_bytes)) = match output {
+        Ok(ref opt) if opt.timed_out => {
+            error!("Command timed out after {} seconds:  {}", check.timeout, check.command);
+            let description = format!("Check command timed out after {} seconds:  ",
+                                      Utc::now().signed_duration_since(executed_at).num_seconds());
+            (CheckResultStatus::UNKNOWN, described(description, opt))
+        },
+        Ok(opt) => match opt.status.code() {
+            Some(code) => {
+                metrics = perfdata::parse(&String::from_utf8_lossy(&opt.stdout));
+                (CheckResultStatus::from_exit_code(code), (captured(&opt.stdout, opt.stdout_bytes), opt.stdout_bytes))
+            },
+            None => {
+                // Terminated by a signal, eg. the OOM killer or a crash.
+                let signal = opt.status.signal().unwrap_or(0);
+                let core_dumped = opt.status.core_dumped();
+                let description = format!("Check command was terminated by signal {}{}",
+                                          signal_name(signal),
+                                          if core_dumped { " (core dumped)" } else { "" });
+                error!("{}:  {}", description, check.command);
+                (CheckResultStatus::UNKNOWN, described(format!("{}:  ", description), &opt))
+            },
         },
         Err(e) => {
             error!("Command failed to run:  {}", e);
-            ClientCheckResultMessage {
-                completed_at: Utc::now(),
-                scheduled_at: check.scheduled_at,
-                executed_at,
-                group: check.group.clone(),
-                name: check.name.clone(),
-                source: String::from(client_name),
-                status: CheckResultStatus::UNKNOWN,
-                output: format!("Failed to run command:  {}", e),
-            }
+            let reason = format!("Failed to run command:  {}", e);
+            let reason_bytes = reason.len();
+            (CheckResultStatus::UNKNOWN, (reason, reason_bytes))
         },
     };
-    Ok(result_msg)
+    let mut result_msg = ClientCheckResultMessage::new(check, client_name, executed_at, status, output_msg);
+    result_msg.output_bytes = output_bytes;
+    if let Some((stderr, stderr_bytes)) = stderr {
+        result_msg.stderr = Some(stderr);
+        result_msg.stderr_bytes = Some(stderr_bytes);
+    }
+    result_msg.metrics = metrics;
+    result_msg
 }
 
-/// Send the result to the results queue to be processed on the backend.
-fn send_result(sqs_client: &SqsClient, queue: &str, message: ClientCheckResultMessage) {
-    let message_body = serde_json::to_string(&message).unwrap();
-    let req = SendMessageRequest {
-        delay_seconds: None,
-        message_attributes: None,
-        message_body,
-        message_deduplication_id: None,  // Only valid for FIFO queues.
-        message_group_id: None,  // Only valid for FIFO queues.
-        queue_url: queue.to_string(),
-    };
-    let res = sqs_client.send_message(req).sync();
-    match res {
-        Ok(r) => debug!("Sent message to result queue:  {}", r.message_id.as_ref().unwrap()),
-        Err(e) => error!("Failed to send message to result queue:  {}", e),
+/// The captured output as text, marked as truncated if the stream was longer than was kept.
+fn captured(output: &[u8], total_bytes: usize) -> String {
+    let text = String::from_utf8_lossy(output).to_string();
+    if total_bytes > output.len() {
+        check::truncate(&text, text.len(), total_bytes)
+    } else {
+        text
     }
 }
 
-/// Delete the message from the queue after it has been processed.
-fn delete_message(sqs_client: &SqsClient, queue: &str, message: &Message) {
-    // Delete message from queue.
-    let del_req = DeleteMessageRequest {
-        queue_url: queue.to_string(),
-        receipt_handle: message.receipt_handle.as_ref().unwrap().to_string(),
+/// The name and number of the signal, eg. `SIGKILL (9)`.
+fn signal_name(signal: i32) -> String {
+    let name = match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        libc::SIGSYS => "SIGSYS",
+        _ => return format!("{}", signal),
     };
-    let del_res = sqs_client.delete_message(del_req).sync();
-    match del_res {
-        Err(e) => error!("Error deleting message:  {:?}", e),
-        Ok(_) => debug!("Deleted message {}", message.message_id.as_ref().unwrap()),
-    }
+    format!("{} ({})", name, signal)
 }
 
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use chrono::DateTime;
 
-    fn generate_sqs_message(command: &str) -> Message {
+    use crate::privileges::RunAs;
+    use tokio::runtime::current_thread::Runtime;
+
+    const MAX_OUTPUT: usize = 65_536;
+    const CLIENT_NAME: &str = "test-client";
+    const SCHEDULED_AT: &str = "2019-01-10T11:07:44Z";
+
+    /// A check running the command, with the fields the tests don't set defaulted.
+    fn check(command: &str) -> ClientCheckMessage {
+        ClientCheckMessage {
+            scheduled_at: SCHEDULED_AT.parse::<DateTime<Utc>>().unwrap(),
+            group: String::from("test"),
+            name: String::from("test-check"),
+            command: String::from(command),
+            timeout: 30,
+            tags: vec![],
+            capture: OutputCapture::Separate,
+            max_age: None,
+            run_as: RunAs::default(),
+        }
+    }
+
+    fn run(check_message: &ClientCheckMessage, client_name: &str) -> ClientCheckResultMessage {
+        Runtime::new().unwrap()
+            .block_on(execute_command(check_message, client_name, MAX_OUTPUT, None, None, |_| {}))
+            .unwrap()
+    }
+
+    fn generate_sqs_message(command: &str) -> ReceivedMessage {
         let body = format!("{{\"scheduledAt\":\"2019-01-10T11:07:44Z\",\"group\":\"test\",\"name\":\"Unknown check\",\"command\":\"{}\",\"timeout\":30,\"tags\":[]}}", command);
-        Message {
-            attributes: None,
-            body: Some(body),
-            md5_of_body: None,
-            md5_of_message_attributes: None,
-            message_attributes: None,
-            message_id: Some(String::from("50aa8ce2-2ba9-5a30-a2b9-d88aa7418f2b")),
-            receipt_handle: None,
+        ReceivedMessage {
+            message_id: String::from("50aa8ce2-2ba9-5a30-a2b9-d88aa7418f2b"),
+            receipt_handle: String::new(),
+            body,
         }
     }
 
@@ -163,27 +390,84 @@ mod test {
     fn parse_sqs_message() {
         const COMMAND: &str = "true";
         let sqs_message = generate_sqs_message(COMMAND);
-        let parsed_message = parse_client_check_message(&sqs_message).unwrap();
+        let parsed_message = parse_client_check_message(&sqs_message, None).unwrap();
         assert_eq!("2019-01-10T11:07:44Z".parse::<DateTime<Utc>>().unwrap(), parsed_message.scheduled_at);
         assert_eq!("test", parsed_message.group);
         assert_eq!("Unknown check", parsed_message.name);
         assert_eq!(30, parsed_message.timeout);
+        assert_eq!(OutputCapture::Separate, parsed_message.capture);
+    }
+
+    #[test]
+    fn expired_checks() {
+        let now = Utc::now();
+        let mut check = parse_client_check_message(&generate_sqs_message("true"), None).unwrap();
+        check.scheduled_at = now - chrono::Duration::seconds(120);
+        assert_eq!(None, expired(&check, 0, now));
+        assert_eq!(None, expired(&check, 300, now));
+        assert_eq!(Some(120), expired(&check, 60, now));
+        // The check's own maximum age takes precedence.
+        check.max_age = Some(300);
+        assert_eq!(None, expired(&check, 60, now));
+        check.max_age = Some(0);
+        assert_eq!(None, expired(&check, 60, now));
+        check.max_age = Some(60);
+        assert_eq!(Some(120), expired(&check, 0, now));
+    }
+
+    /// Run as the `nobody` test user, when the tests run as root and it exists.
+    #[test]
+    fn runs_as_identity() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let identity = match Identity::resolve("nobody", None) {
+            Ok(identity) => identity,
+            Err(_) => return,
+        };
+        let result = Runtime::new().unwrap()
+            .block_on(execute_command(&check("id -u && id -g && id -G"), CLIENT_NAME, MAX_OUTPUT, None, Some(&identity), |_| {}))
+            .unwrap();
+        assert_eq!(CheckResultStatus::OK, result.status);
+        let groups: Vec<String> = identity.groups.iter().map(|gid| gid.to_string()).collect();
+        assert_eq!(format!("{}\n{}\n{}\n", identity.uid, identity.gid, groups.join(" ")), result.output);
+    }
+
+    fn run_with_policy(command: &str, capture: OutputCapture) -> ClientCheckResultMessage {
+        let policy: Policy = toml::from_str("[[allow]]\npath = \"/bin/echo\"\nargs = [\"a*\"]").unwrap();
+        let check_message = ClientCheckMessage { capture, ..check(command) };
+        Runtime::new().unwrap()
+            .block_on(execute_command(&check_message, CLIENT_NAME, MAX_OUTPUT, Some(&policy), None, |_| {}))
+            .unwrap()
+    }
+
+    #[test]
+    fn policy_denies_commands() {
+        for command in ["/bin/sh -c 'echo a'", "/bin/echo b", "/bin/echo a; /bin/echo b"] {
+            let result = run_with_policy(command, OutputCapture::Separate);
+            assert_eq!(CheckResultStatus::UNKNOWN, result.status);
+            assert!(result.output.starts_with("Check denied by client policy"), "{}", command);
+        }
+    }
+
+    #[test]
+    fn policy_runs_allowed_commands_directly() {
+        let result = run_with_policy("/bin/echo 'a  b' a*", OutputCapture::Separate);
+        assert_eq!(CheckResultStatus::OK, result.status);
+        assert_eq!("a  b a*\n", result.output);
+        let result = run_with_policy("/bin/echo a", OutputCapture::Merged);
+        assert_eq!(CheckResultStatus::OK, result.status);
+        assert_eq!("a\n", result.output);
     }
 
     #[test]
     fn execute_command_ok() {
-        const CLIENT_NAME: &str = "test-client";
-        const SCHEDULED_AT: &str = "2019-01-10T11:07:44Z";
         let check_message = ClientCheckMessage {
-            scheduled_at: SCHEDULED_AT.parse::<DateTime<Utc>>().unwrap(),
-            group: String::from("test"),
             name: String::from("ok-check"),
-            command: String::from("echo \"Ok check\" && exit 0"),
-            timeout: 30,
-            tags: vec![],
+            ..check("echo \"Ok check\" && exit 0")
         };
 
-        let result = execute_command(&check_message, CLIENT_NAME).unwrap();
+        let result = run(&check_message, CLIENT_NAME);
         assert_eq!(SCHEDULED_AT.parse::<DateTime<Utc>>().unwrap(), result.scheduled_at);
         assert_eq!("test", result.group);
         assert_eq!("ok-check", result.name);
@@ -191,20 +475,27 @@ mod test {
         assert_eq!("Ok check\n", result.output);
     }
 
+    #[test]
+    fn execute_command_perfdata() {
+        let check_message = check("echo \"Ok check | time=0.5s;1;2;0\" && exit 0");
+
+        let result = run(&check_message, CLIENT_NAME);
+        assert_eq!(CheckResultStatus::OK, result.status);
+        assert_eq!("Ok check | time=0.5s;1;2;0\n", result.output);
+        assert_eq!(1, result.metrics.len());
+        assert_eq!("time", result.metrics[0].label);
+        assert_eq!(Some(0.5), result.metrics[0].value);
+        assert_eq!(Some(String::from("s")), result.metrics[0].uom);
+    }
+
     #[test]
     fn execute_command_critical() {
-        const CLIENT_NAME: &str = "test-client";
-        const SCHEDULED_AT: &str = "2019-01-10T11:07:44Z";
         let check_message = ClientCheckMessage {
-            scheduled_at: SCHEDULED_AT.parse::<DateTime<Utc>>().unwrap(),
-            group: String::from("test"),
             name: String::from("critical-check"),
-            command: String::from("echo \"Critical check\" && exit 2"),
-            timeout: 30,
-            tags: vec![],
+            ..check("echo \"Critical check\" && exit 2")
         };
 
-        let result = execute_command(&check_message, CLIENT_NAME).unwrap();
+        let result = run(&check_message, CLIENT_NAME);
         assert_eq!(SCHEDULED_AT.parse::<DateTime<Utc>>().unwrap(), result.scheduled_at);
         assert_eq!("test", result.group);
         assert_eq!("critical-check", result.name);
@@ -214,18 +505,12 @@ mod test {
 
     #[test]
     fn execute_command_unknown() {
-        const CLIENT_NAME: &str = "test-client";
-        const SCHEDULED_AT: &str = "2019-01-10T11:07:44Z";
         let check_message = ClientCheckMessage {
-            scheduled_at: SCHEDULED_AT.parse::<DateTime<Utc>>().unwrap(),
-            group: String::from("test"),
             name: String::from("unknown-check"),
-            command: String::from("echo \"Unknown check\" && exit 11"),
-            timeout: 30,
-            tags: vec![],
+            ..check("echo \"Unknown check\" && exit 11")
         };
 
-        let result = execute_command(&check_message, CLIENT_NAME).unwrap();
+        let result = run(&check_message, CLIENT_NAME);
         assert_eq!(SCHEDULED_AT.parse::<DateTime<Utc>>().unwrap(), result.scheduled_at);
         assert_eq!("test", result.group);
         assert_eq!("unknown-check", result.name);
@@ -235,39 +520,128 @@ mod test {
 
     #[test]
     fn execute_command_timeout() {
-        const CLIENT_NAME: &str = "test-client";
-        const SCHEDULED_AT: &str = "2019-01-10T11:07:44Z";
         let check_message = ClientCheckMessage {
-            scheduled_at: SCHEDULED_AT.parse::<DateTime<Utc>>().unwrap(),
-            group: String::from("test"),
-            name: String::from("timeout-check"),
-            command: String::from("sleep 30"),
             timeout: 2,
-            tags: vec![],
+            ..check("sleep 30")
         };
 
-        let result = execute_command(&check_message, CLIENT_NAME).unwrap();
+        let result = run(&check_message, CLIENT_NAME);
 
         assert_eq!(CheckResultStatus::UNKNOWN, result.status);
         assert!(result.output.starts_with("Check command timed out"));
     }
 
+    /// Stderr stays in its own field, and the size describes the output sent.
+    #[test]
+    fn execute_command_timeout_separate_stderr() {
+        let check_message = ClientCheckMessage {
+            timeout: 2,
+            ..check("echo \"Out\" && echo \"Err\" >&2 && sleep 30")
+        };
+
+        let result = run(&check_message, CLIENT_NAME);
+
+        assert_eq!(CheckResultStatus::UNKNOWN, result.status);
+        assert!(result.output.starts_with("Check command timed out after "));
+        assert!(result.output.ends_with(":  Out\n"));
+        assert!(!result.output.contains("Err"));
+        assert_eq!(Some(String::from("Err\n")), result.stderr);
+        assert_eq!(result.output.len(), result.output_bytes);
+    }
+
+    /// A check exiting with the code `timeout` uses for timed-out commands
+    /// is not mistaken for a time-out.
+    #[test]
+    fn execute_command_exit_124() {
+        let check_message = check("echo \"Exit 124 check\" && exit 124");
+
+        let result = run(&check_message, CLIENT_NAME);
+
+        assert_eq!(CheckResultStatus::UNKNOWN, result.status);
+        assert_eq!("Exit 124 check\n", result.output);
+    }
+
+    #[test]
+    fn execute_command_killed() {
+        let check_message = check("echo \"Killed check\" && kill -KILL $$");
+
+        let result = run(&check_message, CLIENT_NAME);
+
+        assert_eq!(CheckResultStatus::UNKNOWN, result.status);
+        assert_eq!("Check command was terminated by signal SIGKILL (9):  Killed check\n", result.output);
+    }
+
+    #[test]
+    fn execute_command_crashed() {
+        let check_message = check("ulimit -c 0; kill -SEGV $$");
+
+        let result = run(&check_message, CLIENT_NAME);
+
+        assert_eq!(CheckResultStatus::UNKNOWN, result.status);
+        assert!(result.output.starts_with("Check command was terminated by signal SIGSEGV (11)"));
+        assert!(!result.output.contains("core dumped"));
+    }
+
+    #[test]
+    fn execute_command_separate_stderr() {
+        let check_message = check("echo \"Out\" && echo \"Err\" >&2 && exit 2");
+
+        let result = run(&check_message, CLIENT_NAME);
+
+        assert_eq!(CheckResultStatus::CRITICAL, result.status);
+        assert_eq!("Out\n", result.output);
+        assert_eq!(Some(String::from("Err\n")), result.stderr);
+    }
+
+    #[test]
+    fn execute_command_merged_stderr() {
+        let check_message = ClientCheckMessage {
+            capture: OutputCapture::Merged,
+            ..check("echo \"One\" && echo \"Two\" >&2 && echo \"Three\"")
+        };
+
+        let result = run(&check_message, CLIENT_NAME);
+
+        assert_eq!(CheckResultStatus::OK, result.status);
+        assert_eq!("One\nTwo\nThree\n", result.output);
+        assert_eq!(None, result.stderr);
+    }
+
+    #[test]
+    fn execute_command_stdout_only() {
+        let check_message = ClientCheckMessage {
+            capture: OutputCapture::Stdout,
+            ..check("echo \"Out\" && echo \"Err\" >&2")
+        };
+
+        let result = run(&check_message, CLIENT_NAME);
+
+        assert_eq!("Out\n", result.output);
+        assert_eq!(None, result.stderr);
+    }
+
+    #[test]
+    fn execute_command_truncated() {
+        let check_message = check("yes | head -c 1000000");
+
+        let result = run(&check_message, CLIENT_NAME);
+
+        assert_eq!(CheckResultStatus::OK, result.status);
+        assert_eq!(1_000_000, result.output_bytes);
+        assert!(result.output.starts_with("y\ny\n"));
+        assert!(result.output.ends_with("[... truncated, 65536 of 1000000 bytes shown]"));
+    }
+
     /// Check commands which include pipes or bash operators must
     /// timeout as expected.
     #[test]
     fn execute_command_timeout_complex() {
-        const CLIENT_NAME: &str = "test-client";
-        const SCHEDULED_AT: &str = "2019-01-10T11:07:44Z";
         let check_message = ClientCheckMessage {
-            scheduled_at: SCHEDULED_AT.parse::<DateTime<Utc>>().unwrap(),
-            group: String::from("test"),
-            name: String::from("complex-timeout-check"),
-            command: String::from("sleep 30 || sleep 5"),
             timeout: 2,
-            tags: vec![],
+            ..check("sleep 30 || sleep 5")
         };
 
-        let result = execute_command(&check_message, CLIENT_NAME).unwrap();
+        let result = run(&check_message, CLIENT_NAME);
 
         println!("{:?}", result);
         assert_eq!(CheckResultStatus::UNKNOWN, result.status);
@@ -1,93 +1,800 @@
-use clap::{Arg, App, ArgMatches};
-use clap::{crate_version, crate_name, value_t_or_exit};
+use clap::{Arg, App, ArgMatches, ErrorKind, SubCommand};
+use clap::{crate_version, crate_name};
 use rusoto_core::Region;
 
+use crate::messages::check::MAX_MESSAGE_SIZE;
+use crate::privileges::RunAs;
+use super::settings::{self, Settings};
+
+use std::path::Path;
 use std::str::FromStr;
 
 
+/// Bounds of the `--concurrency` worker count.
+pub const MIN_CONCURRENCY: usize = 1;
+pub const MAX_CONCURRENCY: usize = 256;
+
+/// Defaults of the optional settings.
+const DEFAULT_CONCURRENCY: usize = 10;
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+const DEFAULT_REGISTRATION_INTERVAL: u64 = 300;
+const DEFAULT_MAX_OUTPUT: usize = 65_536;
+const DEFAULT_REPLAY_WINDOW: u64 = 300;
+const DEFAULT_LOG_LEVEL: log::LevelFilter = log::LevelFilter::Info;
+const DEFAULT_SPOOL_DIR: &str = "/var/spool/smdf-client";
+const DEFAULT_SPOOL_MAX_SIZE: u64 = 104_857_600;
+const DEFAULT_SPOOL_MAX_AGE: u64 = 86_400;
+
+
+/// Where to find the ARN of a registration or de-registration Lambda function.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Function {
+    /// The parameter store name holding the ARN.
+    Parameter(String),
+    /// The function ARN itself.
+    Arn(String),
+}
+
+impl Function {
+    /// The function given by the ARN, or else the parameter.
+    /// Falls back to the parameter `/<env>/smdf/<name>` if an environment is set.
+    fn resolve(arn: &Option<String>, parameter: &Option<String>, environ: &Option<String>, name: &str) -> Option<Self> {
+        if let Some(arn) = arn {
+            Some(Function::Arn(arn.clone()))
+        } else if let Some(parameter) = parameter {
+            Some(Function::Parameter(parameter.clone()))
+        } else {
+            environ.as_ref()
+                .map(|environ| Function::Parameter(format!("/{}/smdf/{}", environ, name)))
+        }
+    }
+}
+
+/// Custom service endpoint URLs replacing those of the region,
+/// eg. to run against local emulators such as LocalStack or ElasticMQ.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Endpoints {
+    pub ssm: Option<String>,
+    pub lambda: Option<String>,
+    pub sqs: Option<String>,
+}
+
+/// How the AWS credentials are obtained, see [crate::aws::credentials].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Credentials {
+    /// Named profile of the shared credentials file.
+    pub profile: Option<String>,
+    /// Credentials file to read instead of `~/.aws/credentials`.
+    pub file: Option<String>,
+    /// Token file to assume `role_arn` with web identity federation.
+    pub web_identity_token_file: Option<String>,
+    pub role_arn: Option<String>,
+    /// Required by the role's trust policy in other accounts.
+    pub external_id: Option<String>,
+    pub role_session_name: Option<String>,
+}
+
+/// Where results which could not be sent are kept until they can be, see [crate::spool].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Spool {
+    pub dir: String,
+    /// The maximum total size of the spooled results, in bytes.
+    pub max_size: u64,
+    /// Spooled results older than this many seconds are discarded.
+    pub max_age: u64,
+}
+
 #[derive(Clone, Debug)]
 pub struct Config {
     pub client_name: String,
+    /// Checks are only run if one of their tags matches one of these wildcard patterns.
     pub tags: Vec<String>,
+    /// Checks with a tag matching one of these wildcard patterns are not run.
+    pub exclude_tags: Vec<String>,
     pub region: Region,
-    pub registration_parameter: String,
-    pub deregistration_parameter: String,
+    pub registration: Function,
+    /// Only required with `auto_deregister`.
+    pub deregistration: Option<Function>,
     pub auto_deregister: bool,
     pub concurrency: usize,
+    pub shutdown_timeout: u64,
+    /// Seconds between re-registrations, or 0 to only register on start.
+    pub registration_interval: u64,
+    pub max_output: usize,
+    /// Checks scheduled more than this many seconds ago are skipped, unless 0.
+    /// Overridden by the check's own maximum age.
+    pub max_check_age: u64,
+    /// Send an `UNKNOWN` result for skipped checks.
+    pub report_expired: bool,
+    /// Policy file of the commands checks may run, see [crate::policy].  Any command may be run without one.
+    pub policy: Option<String>,
+    /// Keys file which every command message must be signed by one of, see [crate::signature].
+    /// Signatures are not verified without one.
+    pub signing_keys: Option<String>,
+    /// Seconds from now a signed check may have been scheduled at, either way.
+    pub replay_window: u64,
+    /// The user and group checks run as, unless the check sets its own.  Without a user, checks run as the client.
+    pub run_as: RunAs,
+    /// The users a check may set to run as, besides the client's user.
+    pub allowed_users: Vec<String>,
+    /// Allow checks to run as root.
+    pub allow_root: bool,
     pub log_level: log::LevelFilter,
+    pub endpoints: Endpoints,
+    pub credentials: Credentials,
+    pub spool: Spool,
 }
 
 impl Config {
+    /// The configuration from the configuration file, environment and command line.
+    /// With the `config dump` sub-command, prints the effective configuration and exits.
     pub fn new() -> Self {
         let matches = parse();
-        let environ = matches.value_of("environment").unwrap();
-        let registration_parameter = format!("/{}/smdf/registration", environ);
-        let deregistration_parameter = format!("/{}/smdf/de-registration", environ);
-        Self {
-            client_name: matches.value_of("name").unwrap().to_string(),
-            tags: matches.values_of("tags").unwrap().map(String::from).collect(),
-            region: Region::from_str(matches.value_of("region").unwrap()).unwrap(),
-            registration_parameter,
-            deregistration_parameter,
-            auto_deregister: matches.is_present("auto-deregister"),
-            concurrency: value_t_or_exit!(matches.value_of("concurrency"), usize),
-            log_level: value_t_or_exit!(matches.value_of("log-level"), log::LevelFilter),
+        let config = load(&matches, |name| std::env::var(name).ok())
+            .and_then(Self::from_settings)
+            .unwrap_or_else(|(description, kind)| clap::Error::with_description(&description, kind).exit());
+
+        if let Some(("dump", _)) = matches.subcommand_matches("config").map(|m| m.subcommand()) {
+            match toml::to_string(&config.to_settings()) {
+                Ok(dump) => print!("{}", dump),
+                Err(e) => clap::Error::with_description(&e.to_string(), ErrorKind::Format).exit(),
+            }
+            std::process::exit(0);
+        }
+        config
+    }
+
+    /// Apply the defaults to the merged settings and validate them.
+    pub fn from_settings(settings: Settings) -> Result<Self, (String, ErrorKind)> {
+        let missing = |name: &str| (format!("The required setting `{}` was not provided", name), ErrorKind::MissingRequiredArgument);
+        let invalid = |description: String| (description, ErrorKind::InvalidValue);
+
+        let registration = Function::resolve(&settings.reg_arn, &settings.reg_parameter, &settings.environment, "registration")
+            .ok_or_else(|| (String::from("One of the settings `environment`, `reg-parameter` or `reg-arn` is required"),
+                            ErrorKind::MissingRequiredArgument))?;
+        let deregistration = Function::resolve(&settings.dereg_arn, &settings.dereg_parameter, &settings.environment, "de-registration");
+        let auto_deregister = settings.auto_deregister.unwrap_or(false);
+        if auto_deregister && deregistration.is_none() {
+            return Err((String::from("`auto-deregister` requires one of the settings `environment`, `dereg-parameter` or `dereg-arn`"),
+                        ErrorKind::MissingRequiredArgument));
+        }
+        let tags = settings.tags.ok_or_else(|| missing("tags"))?;
+        if tags.is_empty() {
+            return Err(invalid(String::from("At least one tag is required.")));
+        }
+        let region = settings.region.ok_or_else(|| missing("region"))?;
+        let region = Region::from_str(&region).map_err(|e| invalid(format!("Invalid region `{}`:  {}", region, e)))?;
+
+        let concurrency = settings.concurrency.unwrap_or(DEFAULT_CONCURRENCY);
+        if !(MIN_CONCURRENCY..=MAX_CONCURRENCY).contains(&concurrency) {
+            return Err(invalid(format!("Concurrency must be an integer from {} to {}.", MIN_CONCURRENCY, MAX_CONCURRENCY)));
+        }
+        let max_output = settings.max_output.unwrap_or(DEFAULT_MAX_OUTPUT);
+        if !(1..=MAX_MESSAGE_SIZE).contains(&max_output) {
+            return Err(invalid(format!("Maximum output must be an integer from 1 to {}.", MAX_MESSAGE_SIZE)));
+        }
+        let endpoints = [&settings.ssm_endpoint, &settings.lambda_endpoint, &settings.sqs_endpoint];
+        for endpoint in endpoints.iter().filter_map(|endpoint| endpoint.as_ref()) {
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                return Err(invalid(format!("Invalid endpoint `{}`, expected an http:// or https:// URL.", endpoint)));
+            }
+        }
+        if settings.role_arn.is_none() {
+            if settings.web_identity_token_file.is_some() || settings.external_id.is_some() {
+                return Err((String::from("`web-identity-token-file` and `external-id` require the setting `role-arn`"),
+                            ErrorKind::MissingRequiredArgument));
+            }
+        } else if settings.web_identity_token_file.is_some()
+            && (settings.external_id.is_some() || settings.profile.is_some() || settings.credentials_file.is_some()) {
+            return Err(invalid(String::from("`web-identity-token-file` cannot be combined with `external-id`, `profile` or `credentials-file`.")));
+        }
+        let log_level = match settings.log_level {
+            Some(level) => log::LevelFilter::from_str(&level)
+                .map_err(|_| invalid(format!("Invalid log level `{}`.", level)))?,
+            None => DEFAULT_LOG_LEVEL,
+        };
+
+        Ok(Self {
+            client_name: settings.name.ok_or_else(|| missing("name"))?,
+            tags,
+            exclude_tags: settings.exclude_tags.unwrap_or_default(),
+            region,
+            registration,
+            deregistration,
+            auto_deregister,
+            concurrency,
+            shutdown_timeout: settings.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+            registration_interval: settings.registration_interval.unwrap_or(DEFAULT_REGISTRATION_INTERVAL),
+            max_output,
+            max_check_age: settings.max_check_age.unwrap_or(0),
+            report_expired: settings.report_expired.unwrap_or(false),
+            policy: settings.policy,
+            signing_keys: settings.signing_keys,
+            replay_window: settings.replay_window.unwrap_or(DEFAULT_REPLAY_WINDOW),
+            run_as: RunAs {
+                user: settings.user,
+                group: settings.group,
+            },
+            allowed_users: settings.allowed_users.unwrap_or_default(),
+            allow_root: settings.allow_root.unwrap_or(false),
+            log_level,
+            endpoints: Endpoints {
+                ssm: settings.ssm_endpoint,
+                lambda: settings.lambda_endpoint,
+                sqs: settings.sqs_endpoint,
+            },
+            credentials: Credentials {
+                profile: settings.profile,
+                file: settings.credentials_file,
+                web_identity_token_file: settings.web_identity_token_file,
+                role_arn: settings.role_arn,
+                external_id: settings.external_id,
+                role_session_name: settings.role_session_name,
+            },
+            spool: Spool {
+                dir: settings.spool_dir.unwrap_or_else(|| String::from(DEFAULT_SPOOL_DIR)),
+                max_size: settings.spool_max_size.unwrap_or(DEFAULT_SPOOL_MAX_SIZE),
+                max_age: settings.spool_max_age.unwrap_or(DEFAULT_SPOOL_MAX_AGE),
+            },
+        })
+    }
+
+    /// The region for SSM clients, with its custom endpoint if set.
+    pub fn ssm_region(&self) -> Region {
+        with_endpoint(&self.region, &self.endpoints.ssm)
+    }
+
+    /// The region for Lambda clients, with its custom endpoint if set.
+    pub fn lambda_region(&self) -> Region {
+        with_endpoint(&self.region, &self.endpoints.lambda)
+    }
+
+    /// The region for SQS clients, with its custom endpoint if set.
+    pub fn sqs_region(&self) -> Region {
+        with_endpoint(&self.region, &self.endpoints.sqs)
+    }
+
+    /// The configuration as settings, for printing.
+    pub fn to_settings(&self) -> Settings {
+        let (reg_arn, reg_parameter) = function_settings(&self.registration);
+        let (dereg_arn, dereg_parameter) = match self.deregistration {
+            Some(ref deregistration) => function_settings(deregistration),
+            None => (None, None),
+        };
+        Settings {
+            name: Some(self.client_name.clone()),
+            tags: Some(self.tags.clone()),
+            exclude_tags: Some(self.exclude_tags.clone()),
+            region: Some(self.region.name().to_string()),
+            environment: None,
+            reg_parameter,
+            dereg_parameter,
+            reg_arn,
+            dereg_arn,
+            auto_deregister: Some(self.auto_deregister),
+            concurrency: Some(self.concurrency),
+            shutdown_timeout: Some(self.shutdown_timeout),
+            registration_interval: Some(self.registration_interval),
+            max_output: Some(self.max_output),
+            max_check_age: Some(self.max_check_age),
+            report_expired: Some(self.report_expired),
+            policy: self.policy.clone(),
+            signing_keys: self.signing_keys.clone(),
+            replay_window: Some(self.replay_window),
+            user: self.run_as.user.clone(),
+            group: self.run_as.group.clone(),
+            allowed_users: Some(self.allowed_users.clone()),
+            allow_root: Some(self.allow_root),
+            log_level: Some(self.log_level.to_string()),
+            ssm_endpoint: self.endpoints.ssm.clone(),
+            lambda_endpoint: self.endpoints.lambda.clone(),
+            sqs_endpoint: self.endpoints.sqs.clone(),
+            profile: self.credentials.profile.clone(),
+            credentials_file: self.credentials.file.clone(),
+            web_identity_token_file: self.credentials.web_identity_token_file.clone(),
+            role_arn: self.credentials.role_arn.clone(),
+            external_id: self.credentials.external_id.clone(),
+            role_session_name: self.credentials.role_session_name.clone(),
+            spool_dir: Some(self.spool.dir.clone()),
+            spool_max_size: Some(self.spool.max_size),
+            spool_max_age: Some(self.spool.max_age),
         }
     }
 }
 
+/// The region with its endpoint replaced, keeping the region name for request signing.
+fn with_endpoint(region: &Region, endpoint: &Option<String>) -> Region {
+    match endpoint {
+        Some(endpoint) => Region::Custom {
+            name: region.name().to_string(),
+            endpoint: endpoint.clone(),
+        },
+        None => region.clone(),
+    }
+}
+
+fn function_settings(function: &Function) -> (Option<String>, Option<String>) {
+    match function {
+        Function::Arn(arn) => (Some(arn.clone()), None),
+        Function::Parameter(parameter) => (None, Some(parameter.clone())),
+    }
+}
+
+/// Merge the configuration file, environment variables and command line flags, in that order.
+fn load<F>(matches: &ArgMatches, lookup: F) -> Result<Settings, (String, ErrorKind)>
+    where F: Fn(&str) -> Option<String>
+{
+    let env = Settings::from_env(&lookup).map_err(|e| (e, ErrorKind::InvalidValue))?;
+    let file = match matches.value_of("config-file").map(String::from).or_else(|| lookup("SMDF_CONFIG")) {
+        Some(path) => Settings::from_file(Path::new(&path))
+            .map_err(|e| (format!("Failed to read configuration file {}:  {}", path, e), ErrorKind::Io))?,
+        None => Settings::default(),
+    };
+    let cli = from_matches(matches).map_err(|e| (e, ErrorKind::InvalidValue))?;
+    Ok(file.merge(env).merge(cli))
+}
+
+/// The settings given as command line flags.
+fn from_matches(matches: &ArgMatches) -> Result<Settings, String> {
+    let value = |name: &str| matches.value_of(name).map(String::from);
+    let number = |name: &str| -> Result<Option<usize>, String> {
+        match matches.value_of(name) {
+            Some(value) => value.parse::<usize>()
+                .map(Some)
+                .map_err(|e| format!("Invalid value for --{}:  {}", name, e)),
+            None => Ok(None),
+        }
+    };
+    // A flag and its `--no-` form override each other, the last given taking effect.
+    let flag = |name: &str| {
+        if matches.is_present(name) {
+            Some(true)
+        } else if matches.is_present(format!("no-{}", name)) {
+            Some(false)
+        } else {
+            None
+        }
+    };
+    Ok(Settings {
+        name: value("name"),
+        tags: matches.values_of("tags")
+            .map(|tags| tags.flat_map(settings::split_list).collect()),
+        exclude_tags: matches.values_of("exclude-tags")
+            .map(|tags| tags.flat_map(settings::split_list).collect()),
+        region: value("region"),
+        environment: value("environment"),
+        reg_parameter: value("reg-parameter"),
+        dereg_parameter: value("dereg-parameter"),
+        reg_arn: value("reg-arn"),
+        dereg_arn: value("dereg-arn"),
+        auto_deregister: flag("auto-deregister"),
+        concurrency: number("concurrency")?,
+        shutdown_timeout: number("shutdown-timeout")?.map(|timeout| timeout as u64),
+        registration_interval: number("registration-interval")?.map(|interval| interval as u64),
+        max_output: number("max-output")?,
+        max_check_age: number("max-check-age")?.map(|age| age as u64),
+        report_expired: flag("report-expired"),
+        policy: value("policy"),
+        signing_keys: value("signing-keys"),
+        replay_window: number("replay-window")?.map(|window| window as u64),
+        user: value("user"),
+        group: value("group"),
+        allowed_users: matches.values_of("allowed-users")
+            .map(|users| users.flat_map(settings::split_list).collect()),
+        allow_root: flag("allow-root"),
+        log_level: value("log-level"),
+        ssm_endpoint: value("ssm-endpoint"),
+        lambda_endpoint: value("lambda-endpoint"),
+        sqs_endpoint: value("sqs-endpoint"),
+        profile: value("profile"),
+        credentials_file: value("credentials-file"),
+        web_identity_token_file: value("web-identity-token-file"),
+        role_arn: value("role-arn"),
+        external_id: value("external-id"),
+        role_session_name: value("role-session-name"),
+        spool_dir: value("spool-dir"),
+        spool_max_size: number("spool-max-size")?.map(|size| size as u64),
+        spool_max_age: number("spool-max-age")?.map(|age| age as u64),
+    })
+}
+
 fn parse() -> ArgMatches<'static> {
+    app().get_matches()
+}
+
+fn app() -> App<'static, 'static> {
     App::new(crate_name!())
         .about("SMDF client.")
         .version(crate_version!())
+        .after_help("Settings are read from the configuration file, then SMDF_* environment variables,\n\
+                     then the flags above, later sources taking precedence.\n\
+                     eg. `--max-output` is `max-output` in the file and SMDF_MAX_OUTPUT in the environment.")
+        .subcommand(SubCommand::with_name("config")
+            .about("Configuration commands.")
+            .subcommand(SubCommand::with_name("dump")
+                .about("Print the effective configuration as TOML and exit.")))
+        .arg(Arg::with_name("config-file")
+            .long("config")
+            .help("TOML configuration file, eg. /etc/smdf/client.toml")
+            .required(false)
+            .takes_value(true)
+            .value_name("PATH"))
         .arg(Arg::with_name("log-level")
             .short("l")
             .long("log-level")
-            .help("Log level (TRACE, DEBUG, ERROR, WARN, INFO).")
+            .help("Log level (TRACE, DEBUG, ERROR, WARN, INFO). [default: info]")
             .required(false)
             .takes_value(true)
-            .default_value("info")
             .value_name("LEVEL"))
         .arg(Arg::with_name("region")
             .short("r")
             .long("region")
             .help("AWS region.")
-            .required(true)
+            .required(false)
             .takes_value(true)
             .value_name("REGION"))
         .arg(Arg::with_name("name")
             .short("n")
             .long("name")
             .help("The client-name to be registered with the monitoring backend.")
-            .required(true)
+            .required(false)
             .takes_value(true)
             .value_name("NAME"))
         .arg(Arg::with_name("tags")
             .short("t")
             .long("tags")
-            .help("The check tags to run on this client.")
-            .required(true)
+            .help("The check tags to run on this client, which may use * and ? wildcards.\nChecks without a matching tag are rejected.")
+            .required(false)
+            .takes_value(true)
+            .multiple(true)
+            .value_name("TAG,TAG,..."))
+        .arg(Arg::with_name("exclude-tags")
+            .long("exclude-tags")
+            .help("Reject checks with a tag matching one of these, which may use wildcards.")
+            .required(false)
             .takes_value(true)
             .multiple(true)
             .value_name("TAG,TAG,..."))
         .arg(Arg::with_name("environment")
             .short("e")
             .long("environment")
-            .help("The environment this monitoring client is running under.\nParameter store path /<env>/monitoring/registration will be used.")
-            .required(true)
+            .help("The environment this monitoring client is running under.\nParameter store paths /<env>/smdf/registration and /<env>/smdf/de-registration\nwill be used unless overridden.")
+            .required(false)
             .takes_value(true)
             .value_name("ENV"))
+        .arg(Arg::with_name("reg-parameter")
+            .short("p")
+            .long("reg-parameter")
+            .help("Explicitly set the parameter store name of the registration function ARN.\nOverrides `--environment`.\neg. /dev/test/value")
+            .required(false)
+            .takes_value(true)
+            .conflicts_with("reg-arn")
+            .value_name("PATH"))
+        .arg(Arg::with_name("dereg-parameter")
+            .long("dereg-parameter")
+            .help("Explicitly set the parameter store name of the de-registration function ARN.\nOverrides `--environment`.")
+            .required(false)
+            .takes_value(true)
+            .conflicts_with("dereg-arn")
+            .value_name("PATH"))
+        .arg(Arg::with_name("reg-arn")
+            .long("reg-arn")
+            .help("The registration Lambda function ARN, skipping the parameter store lookup.")
+            .required(false)
+            .takes_value(true)
+            .value_name("ARN"))
+        .arg(Arg::with_name("dereg-arn")
+            .long("dereg-arn")
+            .help("The de-registration Lambda function ARN, skipping the parameter store lookup.")
+            .required(false)
+            .takes_value(true)
+            .value_name("ARN"))
         .arg(Arg::with_name("concurrency")
             .short("c")
             .long("concurrency")
-            .help("The maximum number of checks to run concurrently (1-256).\nNote:  Currently not used.")
+            .help("The maximum number of checks to run concurrently (1-256). [default: 10]")
             .required(false)
             .takes_value(true)
-            .default_value("10")
             .value_name("INT"))
+        .arg(Arg::with_name("shutdown-timeout")
+            .long("shutdown-timeout")
+            .help("Seconds to wait on termination for running checks to finish before killing them.\nKeep 12 seconds below the service manager's stop timeout. [default: 30]")
+            .required(false)
+            .takes_value(true)
+            .value_name("SECONDS"))
+        .arg(Arg::with_name("registration-interval")
+            .long("registration-interval")
+            .help("Seconds between re-registrations, which report the client's status\nand pick up new queues.  0 only registers on start. [default: 300]")
+            .required(false)
+            .takes_value(true)
+            .value_name("SECONDS"))
+        .arg(Arg::with_name("max-output")
+            .long("max-output")
+            .help("The maximum bytes of output captured from each stream of a check (1-262144).\nAny further output is discarded and the result marked as truncated. [default: 65536]")
+            .required(false)
+            .takes_value(true)
+            .value_name("BYTES"))
+        .arg(Arg::with_name("max-check-age")
+            .long("max-check-age")
+            .help("Skip checks scheduled longer ago than this, eg. after an outage.\n0 runs checks however old, unless the check sets its own maximum age. [default: 0]")
+            .required(false)
+            .takes_value(true)
+            .value_name("SECONDS"))
+        .arg(Arg::with_name("report-expired")
+            .long("report-expired")
+            .help("Send an UNKNOWN result for skipped checks, rather than only discarding them.")
+            .required(false)
+            .overrides_with("no-report-expired"))
+        .arg(Arg::with_name("no-report-expired")
+            .long("no-report-expired")
+            .help("Only discard skipped checks, even if the file or environment says to report them.")
+            .required(false)
+            .overrides_with("report-expired"))
+        .arg(Arg::with_name("policy")
+            .long("policy")
+            .help("Policy file allowing the commands checks may run, eg. /etc/smdf/policy.toml\nWithout one, checks may run any shell command.")
+            .required(false)
+            .takes_value(true)
+            .value_name("PATH"))
+        .arg(Arg::with_name("signing-keys")
+            .long("signing-keys")
+            .help("Keys file which command messages must be signed by, eg. /etc/smdf/keys.toml\nWithout one, signatures are not verified.")
+            .required(false)
+            .takes_value(true)
+            .value_name("PATH"))
+        .arg(Arg::with_name("replay-window")
+            .long("replay-window")
+            .help("Reject signed checks scheduled further than this from now, and repeats within it. [default: 300]")
+            .required(false)
+            .takes_value(true)
+            .value_name("SECONDS"))
+        .arg(Arg::with_name("user")
+            .long("user")
+            .help("The user checks run as, unless the check sets its own.\nWithout one, checks run as the client's user.")
+            .required(false)
+            .takes_value(true)
+            .value_name("USER"))
+        .arg(Arg::with_name("group")
+            .long("group")
+            .help("The group checks run as. [default: the user's primary group]")
+            .required(false)
+            .takes_value(true)
+            .value_name("GROUP"))
+        .arg(Arg::with_name("allowed-users")
+            .long("allowed-users")
+            .help("The users a check may set to run as, besides `--user`.\nWithout any, checks may not set a user.")
+            .required(false)
+            .takes_value(true)
+            .multiple(true)
+            .value_name("USER,..."))
+        .arg(Arg::with_name("allow-root")
+            .long("allow-root")
+            .help("Allow checks to run as root, which are otherwise refused.")
+            .required(false)
+            .overrides_with("no-allow-root"))
+        .arg(Arg::with_name("no-allow-root")
+            .long("no-allow-root")
+            .help("Refuse checks running as root, even if the file or environment allows them.")
+            .required(false)
+            .overrides_with("allow-root"))
+        .arg(Arg::with_name("ssm-endpoint")
+            .long("ssm-endpoint")
+            .help("Custom SSM endpoint URL, eg. http://localhost:4566 for LocalStack.")
+            .required(false)
+            .takes_value(true)
+            .value_name("URL"))
+        .arg(Arg::with_name("lambda-endpoint")
+            .long("lambda-endpoint")
+            .help("Custom Lambda endpoint URL.")
+            .required(false)
+            .takes_value(true)
+            .value_name("URL"))
+        .arg(Arg::with_name("sqs-endpoint")
+            .long("sqs-endpoint")
+            .help("Custom SQS endpoint URL, eg. http://localhost:9324 for ElasticMQ.")
+            .required(false)
+            .takes_value(true)
+            .value_name("URL"))
+        .arg(Arg::with_name("profile")
+            .long("profile")
+            .help("Named profile of the AWS shared credentials file, instead of the default credential chain.")
+            .required(false)
+            .takes_value(true)
+            .value_name("NAME"))
+        .arg(Arg::with_name("credentials-file")
+            .long("credentials-file")
+            .help("AWS credentials file to read the profile from. [default: ~/.aws/credentials]")
+            .required(false)
+            .takes_value(true)
+            .value_name("PATH"))
+        .arg(Arg::with_name("web-identity-token-file")
+            .long("web-identity-token-file")
+            .help("OIDC token file to assume `--role-arn` with web identity federation, eg. on EKS.")
+            .required(false)
+            .takes_value(true)
+            .value_name("PATH"))
+        .arg(Arg::with_name("role-arn")
+            .long("role-arn")
+            .help("IAM role to assume via STS for all AWS requests.\nThe session is refreshed before it expires.")
+            .required(false)
+            .takes_value(true)
+            .value_name("ARN"))
+        .arg(Arg::with_name("external-id")
+            .long("external-id")
+            .help("External ID required to assume `--role-arn`.")
+            .required(false)
+            .takes_value(true)
+            .value_name("ID"))
+        .arg(Arg::with_name("role-session-name")
+            .long("role-session-name")
+            .help("Session name of the assumed role. [default: smdf-client]")
+            .required(false)
+            .takes_value(true)
+            .value_name("NAME"))
+        .arg(Arg::with_name("spool-dir")
+            .long("spool-dir")
+            .help("Directory results are kept in while the result queue is unreachable.\nA check's command message is only deleted once its result is sent or spooled.\n[default: /var/spool/smdf-client]")
+            .required(false)
+            .takes_value(true)
+            .value_name("PATH"))
+        .arg(Arg::with_name("spool-max-size")
+            .long("spool-max-size")
+            .help("The maximum total size of the spooled results. [default: 104857600]")
+            .required(false)
+            .takes_value(true)
+            .value_name("BYTES"))
+        .arg(Arg::with_name("spool-max-age")
+            .long("spool-max-age")
+            .help("Spooled results older than this are discarded. [default: 86400]")
+            .required(false)
+            .takes_value(true)
+            .value_name("SECONDS"))
         .arg(Arg::with_name("auto-deregister")
             .long("auto-deregister")
             .help("Automatically de-register/de-activate the client on termination.")
-            .required(false))
-        .get_matches()
+            .required(false)
+            .overrides_with("no-auto-deregister"))
+        .arg(Arg::with_name("no-auto-deregister")
+            .long("no-auto-deregister")
+            .help("Don't de-register on termination, even if the file or environment says to.")
+            .required(false)
+            .overrides_with("auto-deregister"))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn settings() -> Settings {
+        Settings {
+            name: Some(String::from("web-01")),
+            tags: Some(vec![String::from("web")]),
+            region: Some(String::from("us-east-1")),
+            environment: Some(String::from("prod")),
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let config = Config::from_settings(settings()).unwrap();
+        assert_eq!(DEFAULT_CONCURRENCY, config.concurrency);
+        assert_eq!(DEFAULT_MAX_OUTPUT, config.max_output);
+        assert_eq!(DEFAULT_LOG_LEVEL, config.log_level);
+        assert_eq!(DEFAULT_SPOOL_DIR, config.spool.dir);
+        assert_eq!(DEFAULT_REGISTRATION_INTERVAL, config.registration_interval);
+        assert!(!config.auto_deregister);
+        assert_eq!(Function::Parameter(String::from("/prod/smdf/registration")), config.registration);
+        assert_eq!(Some(Function::Parameter(String::from("/prod/smdf/de-registration"))), config.deregistration);
+    }
+
+    #[test]
+    fn arn_overrides_environment() {
+        let config = Config::from_settings(Settings {
+            reg_arn: Some(String::from("arn:aws:lambda:us-east-1:123456789012:function:register")),
+            ..settings()
+        }).unwrap();
+        assert_eq!(Function::Arn(String::from("arn:aws:lambda:us-east-1:123456789012:function:register")), config.registration);
+    }
+
+    #[test]
+    fn missing_required_settings() {
+        let (_, kind) = Config::from_settings(Settings { name: None, ..settings() }).unwrap_err();
+        assert_eq!(ErrorKind::MissingRequiredArgument, kind);
+        let (_, kind) = Config::from_settings(Settings { environment: None, ..settings() }).unwrap_err();
+        assert_eq!(ErrorKind::MissingRequiredArgument, kind);
+        let (_, kind) = Config::from_settings(Settings {
+            environment: None,
+            reg_parameter: Some(String::from("/smdf/registration")),
+            auto_deregister: Some(true),
+            ..settings()
+        }).unwrap_err();
+        assert_eq!(ErrorKind::MissingRequiredArgument, kind);
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let (_, kind) = Config::from_settings(Settings { concurrency: Some(0), ..settings() }).unwrap_err();
+        assert_eq!(ErrorKind::InvalidValue, kind);
+        let (_, kind) = Config::from_settings(Settings { log_level: Some(String::from("loud")), ..settings() }).unwrap_err();
+        assert_eq!(ErrorKind::InvalidValue, kind);
+    }
+
+    #[test]
+    fn custom_endpoints() {
+        let config = Config::from_settings(Settings {
+            sqs_endpoint: Some(String::from("http://localhost:9324")),
+            ..settings()
+        }).unwrap();
+        assert_eq!(Region::Custom {
+            name: String::from("us-east-1"),
+            endpoint: String::from("http://localhost:9324"),
+        }, config.sqs_region());
+        assert_eq!(Region::UsEast1, config.ssm_region());
+
+        let (_, kind) = Config::from_settings(Settings {
+            lambda_endpoint: Some(String::from("localhost:4566")),
+            ..settings()
+        }).unwrap_err();
+        assert_eq!(ErrorKind::InvalidValue, kind);
+    }
+
+    #[test]
+    fn credentials() {
+        let config = Config::from_settings(Settings {
+            profile: Some(String::from("monitoring")),
+            role_arn: Some(String::from("arn:aws:iam::123456789012:role/monitoring")),
+            external_id: Some(String::from("smdf")),
+            ..settings()
+        }).unwrap();
+        assert_eq!(Some(String::from("monitoring")), config.credentials.profile);
+        assert_eq!(Some(String::from("smdf")), config.credentials.external_id);
+
+        let (_, kind) = Config::from_settings(Settings {
+            external_id: Some(String::from("smdf")),
+            ..settings()
+        }).unwrap_err();
+        assert_eq!(ErrorKind::MissingRequiredArgument, kind);
+
+        let (_, kind) = Config::from_settings(Settings {
+            web_identity_token_file: Some(String::from("/var/run/secrets/token")),
+            role_arn: Some(String::from("arn:aws:iam::123456789012:role/monitoring")),
+            profile: Some(String::from("monitoring")),
+            ..settings()
+        }).unwrap_err();
+        assert_eq!(ErrorKind::InvalidValue, kind);
+    }
+
+    /// The flags turn the boolean settings on or off, whatever the file and environment say.
+    #[test]
+    fn boolean_flags_override_both_ways() {
+        let load_with = |args: &[&str], env_value: &'static str| {
+            let matches = app().get_matches_from([&["smdf-client"], args].concat());
+            load(&matches, |name| match name {
+                "SMDF_AUTO_DEREGISTER" | "SMDF_REPORT_EXPIRED" | "SMDF_ALLOW_ROOT" => Some(String::from(env_value)),
+                _ => None,
+            }).unwrap()
+        };
+        let settings = load_with(&["--no-auto-deregister", "--no-report-expired", "--no-allow-root"], "true");
+        assert_eq!((Some(false), Some(false), Some(false)),
+                   (settings.auto_deregister, settings.report_expired, settings.allow_root));
+        let settings = load_with(&["--auto-deregister", "--report-expired", "--allow-root"], "false");
+        assert_eq!((Some(true), Some(true), Some(true)),
+                   (settings.auto_deregister, settings.report_expired, settings.allow_root));
+        let settings = load_with(&[], "true");
+        assert_eq!((Some(true), Some(true), Some(true)),
+                   (settings.auto_deregister, settings.report_expired, settings.allow_root));
+        // The last one given wins.
+        let settings = load_with(&["--allow-root", "--no-allow-root"], "false");
+        assert_eq!(Some(false), settings.allow_root);
+    }
+
+    #[test]
+    fn dump_round_trip() {
+        let config = Config::from_settings(Settings { concurrency: Some(4), ..settings() }).unwrap();
+        let dump = toml::to_string(&config.to_settings()).unwrap();
+        let reloaded = Config::from_settings(toml::from_str(&dump).unwrap()).unwrap();
+        assert_eq!(config.to_settings(), reloaded.to_settings());
+    }
 }
@@ -0,0 +1,262 @@
+//! Layered configuration sources.
+//! Every setting may be given in the configuration file, as an `SMDF_*` environment variable,
+//! or as a command line flag.  Each source is read into [Settings] and merged in that order,
+//! with later sources taking precedence, before the defaults fill in anything left unset.
+//!
+//! The file keys match the long flag names, eg. `--max-output` is `max-output` in the file
+//! and `SMDF_MAX_OUTPUT` in the environment.
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+
+/// The environment variable prefix.
+pub const ENV_PREFIX: &str = "SMDF_";
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Settings {
+    pub name: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub exclude_tags: Option<Vec<String>>,
+    pub region: Option<String>,
+    pub environment: Option<String>,
+    pub reg_parameter: Option<String>,
+    pub dereg_parameter: Option<String>,
+    pub reg_arn: Option<String>,
+    pub dereg_arn: Option<String>,
+    pub auto_deregister: Option<bool>,
+    pub concurrency: Option<usize>,
+    pub shutdown_timeout: Option<u64>,
+    pub registration_interval: Option<u64>,
+    pub max_output: Option<usize>,
+    pub max_check_age: Option<u64>,
+    pub report_expired: Option<bool>,
+    pub policy: Option<String>,
+    pub signing_keys: Option<String>,
+    pub replay_window: Option<u64>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub allowed_users: Option<Vec<String>>,
+    pub allow_root: Option<bool>,
+    pub log_level: Option<String>,
+    pub ssm_endpoint: Option<String>,
+    pub lambda_endpoint: Option<String>,
+    pub sqs_endpoint: Option<String>,
+    pub profile: Option<String>,
+    pub credentials_file: Option<String>,
+    pub web_identity_token_file: Option<String>,
+    pub role_arn: Option<String>,
+    pub external_id: Option<String>,
+    pub role_session_name: Option<String>,
+    pub spool_dir: Option<String>,
+    pub spool_max_size: Option<u64>,
+    pub spool_max_age: Option<u64>,
+}
+
+impl Settings {
+    /// Read the TOML configuration file.
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+
+    /// Read the `SMDF_*` variables using `lookup`, eg. `|name| std::env::var(name).ok()`.
+    /// `SMDF_TAGS` is a comma separated list.
+    pub fn from_env<F>(lookup: F) -> Result<Self, String>
+        where F: Fn(&str) -> Option<String>
+    {
+        let var = |name: &str| lookup(&format!("{}{}", ENV_PREFIX, name));
+        Ok(Self {
+            name: var("NAME"),
+            tags: var("TAGS").map(|tags| split_list(&tags)),
+            exclude_tags: var("EXCLUDE_TAGS").map(|tags| split_list(&tags)),
+            region: var("REGION"),
+            environment: var("ENVIRONMENT"),
+            reg_parameter: var("REG_PARAMETER"),
+            dereg_parameter: var("DEREG_PARAMETER"),
+            reg_arn: var("REG_ARN"),
+            dereg_arn: var("DEREG_ARN"),
+            auto_deregister: parse_var("AUTO_DEREGISTER", var("AUTO_DEREGISTER"), parse_bool)?,
+            concurrency: parse_var("CONCURRENCY", var("CONCURRENCY"), usize::from_str)?,
+            shutdown_timeout: parse_var("SHUTDOWN_TIMEOUT", var("SHUTDOWN_TIMEOUT"), u64::from_str)?,
+            registration_interval: parse_var("REGISTRATION_INTERVAL", var("REGISTRATION_INTERVAL"), u64::from_str)?,
+            max_output: parse_var("MAX_OUTPUT", var("MAX_OUTPUT"), usize::from_str)?,
+            max_check_age: parse_var("MAX_CHECK_AGE", var("MAX_CHECK_AGE"), u64::from_str)?,
+            report_expired: parse_var("REPORT_EXPIRED", var("REPORT_EXPIRED"), parse_bool)?,
+            policy: var("POLICY"),
+            signing_keys: var("SIGNING_KEYS"),
+            replay_window: parse_var("REPLAY_WINDOW", var("REPLAY_WINDOW"), u64::from_str)?,
+            user: var("USER"),
+            group: var("GROUP"),
+            allowed_users: var("ALLOWED_USERS").map(|users| split_list(&users)),
+            allow_root: parse_var("ALLOW_ROOT", var("ALLOW_ROOT"), parse_bool)?,
+            log_level: var("LOG_LEVEL"),
+            ssm_endpoint: var("SSM_ENDPOINT"),
+            lambda_endpoint: var("LAMBDA_ENDPOINT"),
+            sqs_endpoint: var("SQS_ENDPOINT"),
+            profile: var("PROFILE"),
+            credentials_file: var("CREDENTIALS_FILE"),
+            web_identity_token_file: var("WEB_IDENTITY_TOKEN_FILE"),
+            role_arn: var("ROLE_ARN"),
+            external_id: var("EXTERNAL_ID"),
+            role_session_name: var("ROLE_SESSION_NAME"),
+            spool_dir: var("SPOOL_DIR"),
+            spool_max_size: parse_var("SPOOL_MAX_SIZE", var("SPOOL_MAX_SIZE"), u64::from_str)?,
+            spool_max_age: parse_var("SPOOL_MAX_AGE", var("SPOOL_MAX_AGE"), u64::from_str)?,
+        })
+    }
+
+    /// Merge the settings, those set in `over` taking precedence.
+    pub fn merge(self, over: Settings) -> Self {
+        Self {
+            name: over.name.or(self.name),
+            tags: over.tags.or(self.tags),
+            exclude_tags: over.exclude_tags.or(self.exclude_tags),
+            region: over.region.or(self.region),
+            environment: over.environment.or(self.environment),
+            reg_parameter: over.reg_parameter.or(self.reg_parameter),
+            dereg_parameter: over.dereg_parameter.or(self.dereg_parameter),
+            reg_arn: over.reg_arn.or(self.reg_arn),
+            dereg_arn: over.dereg_arn.or(self.dereg_arn),
+            auto_deregister: over.auto_deregister.or(self.auto_deregister),
+            concurrency: over.concurrency.or(self.concurrency),
+            shutdown_timeout: over.shutdown_timeout.or(self.shutdown_timeout),
+            registration_interval: over.registration_interval.or(self.registration_interval),
+            max_output: over.max_output.or(self.max_output),
+            max_check_age: over.max_check_age.or(self.max_check_age),
+            report_expired: over.report_expired.or(self.report_expired),
+            policy: over.policy.or(self.policy),
+            signing_keys: over.signing_keys.or(self.signing_keys),
+            replay_window: over.replay_window.or(self.replay_window),
+            user: over.user.or(self.user),
+            group: over.group.or(self.group),
+            allowed_users: over.allowed_users.or(self.allowed_users),
+            allow_root: over.allow_root.or(self.allow_root),
+            log_level: over.log_level.or(self.log_level),
+            ssm_endpoint: over.ssm_endpoint.or(self.ssm_endpoint),
+            lambda_endpoint: over.lambda_endpoint.or(self.lambda_endpoint),
+            sqs_endpoint: over.sqs_endpoint.or(self.sqs_endpoint),
+            profile: over.profile.or(self.profile),
+            credentials_file: over.credentials_file.or(self.credentials_file),
+            web_identity_token_file: over.web_identity_token_file.or(self.web_identity_token_file),
+            role_arn: over.role_arn.or(self.role_arn),
+            external_id: over.external_id.or(self.external_id),
+            role_session_name: over.role_session_name.or(self.role_session_name),
+            spool_dir: over.spool_dir.or(self.spool_dir),
+            spool_max_size: over.spool_max_size.or(self.spool_max_size),
+            spool_max_age: over.spool_max_age.or(self.spool_max_age),
+        }
+    }
+}
+
+/// Split a comma separated list, dropping empty items.
+pub fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn parse_var<T, E, F>(name: &str, value: Option<String>, parse: F) -> Result<Option<T>, String>
+    where F: Fn(&str) -> Result<T, E>, E: std::fmt::Display
+{
+    match value {
+        Some(value) => parse(value.trim())
+            .map(Some)
+            .map_err(|e| format!("Invalid value for {}{}:  {}", ENV_PREFIX, name, e)),
+        None => Ok(None),
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "true" | "1" | "yes" => Ok(true),
+        "false" | "0" | "no" | "" => Ok(false),
+        _ => Err(format!("expected true or false, got `{}`", value)),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> Result<Settings, String> {
+        let vars: HashMap<String, String> = vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Settings::from_env(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn parse_file() {
+        let settings: Settings = toml::from_str(r#"
+            name = "web-01"
+            tags = ["web", "linux"]
+            region = "us-east-1"
+            reg-arn = "arn:aws:lambda:us-east-1:123456789012:function:register"
+            auto-deregister = true
+            concurrency = 4
+        "#).unwrap();
+        assert_eq!(Some(String::from("web-01")), settings.name);
+        assert_eq!(Some(vec![String::from("web"), String::from("linux")]), settings.tags);
+        assert_eq!(Some(true), settings.auto_deregister);
+        assert_eq!(Some(4), settings.concurrency);
+        assert_eq!(None, settings.environment);
+    }
+
+    #[test]
+    fn unknown_file_keys_are_rejected() {
+        assert!(toml::from_str::<Settings>("concurency = 4").is_err());
+    }
+
+    #[test]
+    fn parse_env() {
+        let settings = env(&[
+            ("SMDF_NAME", "web-01"),
+            ("SMDF_TAGS", "web, linux,"),
+            ("SMDF_AUTO_DEREGISTER", "True"),
+            ("SMDF_MAX_OUTPUT", "1024"),
+            ("NAME", "ignored"),
+        ]).unwrap();
+        assert_eq!(Some(String::from("web-01")), settings.name);
+        assert_eq!(Some(vec![String::from("web"), String::from("linux")]), settings.tags);
+        assert_eq!(Some(true), settings.auto_deregister);
+        assert_eq!(Some(1024), settings.max_output);
+        assert_eq!(None, settings.concurrency);
+    }
+
+    #[test]
+    fn invalid_env() {
+        let err = env(&[("SMDF_CONCURRENCY", "many")]).unwrap_err();
+        assert!(err.contains("SMDF_CONCURRENCY"));
+    }
+
+    #[test]
+    fn later_sources_take_precedence() {
+        let file = Settings {
+            name: Some(String::from("file")),
+            region: Some(String::from("us-east-1")),
+            concurrency: Some(4),
+            ..Settings::default()
+        };
+        let env = Settings {
+            name: Some(String::from("env")),
+            concurrency: Some(8),
+            ..Settings::default()
+        };
+        let cli = Settings {
+            concurrency: Some(16),
+            ..Settings::default()
+        };
+        let merged = file.merge(env).merge(cli);
+        assert_eq!(Some(String::from("env")), merged.name);
+        assert_eq!(Some(String::from("us-east-1")), merged.region);
+        assert_eq!(Some(16), merged.concurrency);
+    }
+}
@@ -1,13 +1,13 @@
 use std::error::Error;
 
-use rusoto_core::Region;
 use rusoto_ssm::{
     SsmClient, Ssm, GetParameterRequest
 };
 
+use super::cli::Function;
 
-pub fn get_registration_arn(region: &Region, parameter: &str) -> Result<String, Box<dyn Error>> {
-    let ssm_client = SsmClient::new(region.clone());
+
+pub fn get_registration_arn(ssm_client: &SsmClient, parameter: &str) -> Result<String, Box<dyn Error>> {
     let req = GetParameterRequest {
         name: parameter.to_string(),
         with_decryption: None
@@ -15,3 +15,11 @@ pub fn get_registration_arn(region: &Region, parameter: &str) -> Result<String,
     let res = ssm_client.get_parameter(req).sync()?;
     Ok(res.parameter.unwrap().value.unwrap())
 }
+
+/// The ARN of the function, looking it up in the parameter store if needed.
+pub fn get_function_arn(ssm_client: &SsmClient, function: &Function) -> Result<String, Box<dyn Error>> {
+    match function {
+        Function::Arn(arn) => Ok(arn.clone()),
+        Function::Parameter(parameter) => get_registration_arn(ssm_client, parameter),
+    }
+}
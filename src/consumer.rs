@@ -1,127 +1,951 @@
 use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use log::{debug, error, info};
-use rusoto_sqs::{
-    SqsClient, Sqs, ReceiveMessageRequest,
-};
+use futures::Future;
+use futures::future::{self, Loop};
+use futures::sync::oneshot;
+use log::{debug, error, info, warn};
+use tokio::runtime::{self, Runtime, TaskExecutor};
+use tokio::timer::{Delay, Timeout};
 
 use crate::aws::{
     deregistration, registration,
     RegistrationError, RegistrationRequest
 };
-use crate::check_executor::CheckExecutor;
+use crate::aws::clients::Clients;
+use crate::backoff::Backoff;
+use crate::batch::{self, Batcher};
+use crate::check_executor::{CheckExecutor, CompletedCheck};
 use crate::config::cli::Config;
 use crate::config::ssm;
+use crate::deadline::Worker;
+use crate::heartbeat::{Register, RegistrationHeartbeat};
+use crate::in_flight::InFlight;
+use crate::metrics;
+use crate::policy::Policy;
+use crate::privileges::{Identities, RunAs};
+use crate::signature::Verifier;
+use crate::tags;
+use crate::messages::check::ClientCheckResultMessage;
+use crate::slots::Slots;
+use crate::spool::{Replay, Spool};
+use crate::transport::{CommandSource, ErrorKind, ReceiveError, ResultSink};
+use crate::transport::sqs::SqsTransport;
+use crate::visibility::VisibilityHeartbeat;
+
 
+/// Visibility time-out of received command messages, in seconds.
+/// Extended by the [VisibilityHeartbeat] for checks that run longer.
+const RECEIVE_VISIBILITY_TIMEOUT: u64 = 300;
+/// How long to wait for the checks to wind down after killing them.
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(2);
+/// How long after the checks are killed the results, spool replay and de-registration have to finish.
+/// Whatever is still unfinished is abandoned, unsent results being spooled, so that the client exits
+/// within `shutdown-timeout` plus this and the [KILL_GRACE_PERIOD], below the service manager's stop timeout.
+const FINAL_STEPS_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for an idle slot before checking whether the consumer has been stopped.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// The ceiling of the delay before receiving again after the first failed receive, doubling with each failure.
+const RECEIVE_BACKOFF_BASE: Duration = Duration::from_secs(1);
+/// The longest delay before receiving again after failed receives.
+const RECEIVE_BACKOFF_MAX: Duration = Duration::from_secs(60);
 
 pub struct Consumer {
     config: Config,
-    stop: AtomicBool,
-    command_queue: String,
-    result_queue: String,
+    /// The AWS clients, when registered with the monitoring service.
+    clients: Option<Clients>,
+    /// Registers again, periodically and when the command queue no longer exists.
+    reregister: Option<Register>,
+    runtime: Mutex<Runtime>,
+    stop: Arc<AtomicBool>,
+    stopped_at: Mutex<Option<Instant>>,
+    /// The error which stopped the consumer by itself.
+    error: Mutex<Option<String>>,
+    source: Arc<dyn CommandSource>,
+    slots: Slots,
+    in_flight: Arc<InFlight>,
+    results: Arc<Batcher<CompletedCheck>>,
+    deletions: Arc<Batcher<String>>,
+    replay: Replay,
+    policy: Option<Arc<Policy>>,
+    verifier: Option<Arc<Verifier>>,
+    identities: Arc<Identities>,
 }
 
 impl Consumer {
     /// Register the client with the monitoring service.
     pub fn new(config: Config) -> Result<Self, Box<dyn Error>> {
-        // Get registration endpoint.
-        let registration_arn = ssm::get_registration_arn(&config.region, &config.registration_parameter)?;
-        info!("Registration ARN:  {}", registration_arn);
-
-        // Register
-        let reg_req = registration::Request::new(&config.client_name, &config.tags);
-        debug!("Registration request:  {:?}", reg_req);
-        let reg_res = reg_req.execute(&config.region, &registration_arn)?;
+        let started_at = Instant::now();
+        let clients = Clients::new(&config)?;
+        let reg_res = register(&config, &clients, 0, 0)?;
         info!("Registered as {}", config.client_name);
         info!("Command queue:  {}", reg_res.command_queue);
         info!("Result queue:  {}", reg_res.result_queue);
+        let transport = Arc::new(SqsTransport::new(clients.sqs(), reg_res.command_queue, reg_res.result_queue));
+        let mut consumer = Self::with_transport(config.clone(), transport.clone(), transport.clone())?;
+
+        // Later registrations report the client's status, and any new queues are switched to.
+        let reg_clients = clients.clone();
+        let in_flight = consumer.in_flight.clone();
+        consumer.reregister = Some(Arc::new(move || {
+            let reg_res = register(&config, &reg_clients, started_at.elapsed().as_secs(), in_flight.len())?;
+            transport.set_queues(reg_res.command_queue, reg_res.result_queue);
+            Ok(())
+        }));
+        consumer.clients = Some(clients);
+        Ok(consumer)
+    }
+
+    /// Create a consumer receiving check commands from `source` and sending the results to `sink`,
+    /// without registering with the monitoring service.
+    pub fn with_transport(config: Config, source: Arc<dyn CommandSource>, sink: Arc<dyn ResultSink>)
+                          -> Result<Self, Box<dyn Error>>
+    {
+        let runtime = runtime::Builder::new()
+            .name_prefix("runtime-")
+            .build()?;
+        let slots = Slots::new(config.concurrency);
+        let policy = match config.policy {
+            Some(ref path) => Some(Arc::new(Policy::from_file(Path::new(path))
+                .map_err(|e| format!("Failed to load policy file {}:  {}", path, e))?)),
+            None => {
+                warn!("No policy file configured, checks may run any command.");
+                None
+            },
+        };
+        let verifier = match config.signing_keys {
+            Some(ref path) => Some(Arc::new(Verifier::from_file(Path::new(path), Duration::from_secs(config.replay_window))
+                .map_err(|e| format!("Failed to load signing keys file {}:  {}", path, e))?)),
+            None => {
+                warn!("No signing keys file configured, command messages are not verified.");
+                None
+            },
+        };
+        // The client's own identity is resolved up front, rather than when the first check runs.
+        let identities = Arc::new(Identities::new(config.run_as.clone(), config.allowed_users.clone(), config.allow_root));
+        if let Err(e) = identities.for_check(&RunAs::default()) {
+            warn!("Checks not setting their own user will be refused:  {}", e);
+        }
+        let spool = Spool::open(Path::new(&config.spool.dir), config.spool.max_size,
+                                Duration::from_secs(config.spool.max_age))
+            .map_err(|e| format!("Failed to open spool directory {}:  {}", config.spool.dir, e))?;
+        let spool = Arc::new(spool);
+        let replay = Replay::start(spool.clone(), sink.clone());
+
+        // Results and deletions are sent in batches.
+        // A message is only deleted once its result has been sent, or spooled if that fails.
+        // A result the queue rejects is replaced by an UNKNOWN result giving the reason, and if that
+        // is rejected too the message is left on the queue.
+        // Messages stay in flight, their visibility extended, until deleted or left on the queue.
+        let in_flight = Arc::new(InFlight::new());
+        let deletions_source = source.clone();
+        let deleted_in_flight = in_flight.clone();
+        let undeleted_in_flight = in_flight.clone();
+        let deletions = Arc::new(Batcher::with_fallback(
+            "deletions",
+            move |receipt_handles: &[String]| {
+                let failed = deletions_source.delete(receipt_handles);
+                for (index, receipt_handle) in receipt_handles.iter().enumerate() {
+                    if !failed.contains(&index) {
+                        deleted_in_flight.remove(receipt_handle);
+                    }
+                }
+                failed
+            },
+            // Delivery is at least once: the message becomes visible again, and its check is run again.
+            move |receipt_handle: String| {
+                error!("Failed to delete the message with receipt handle {}, it will be received again.", receipt_handle);
+                undeleted_in_flight.remove(&receipt_handle);
+            }));
+        let sent_deletions = deletions.clone();
+        let spooled_deletions = deletions.clone();
+        let rejected_in_flight = in_flight.clone();
+        let unspooled_in_flight = in_flight.clone();
+        let results = Batcher::with_fallback(
+            "results",
+            move |completed: &[CompletedCheck]| {
+                let results: Vec<ClientCheckResultMessage> = completed.iter()
+                    .map(|check| check.result.clone())
+                    .collect();
+                let failed = sink.send(&results);
+                for (index, check) in completed.iter().enumerate() {
+                    if let Some(reason) = failed.rejected(index) {
+                        let replacement = check.result.replacement(format!("Result rejected by the result queue:  {}", reason));
+                        if sink.send(&[replacement]).is_empty() {
+                            sent_deletions.push(check.receipt_handle.clone());
+                        } else {
+                            error!("Failed to send the result of {}/{}, leaving its message on the queue.",
+                                   check.result.group, check.result.name);
+                            rejected_in_flight.remove(&check.receipt_handle);
+                        }
+                    } else if failed.sent(index) {
+                        sent_deletions.push(check.receipt_handle.clone());
+                    }
+                }
+                failed.retryable
+            },
+            move |check: CompletedCheck| {
+                let (group, name) = (&check.result.group, &check.result.name);
+                match spool.write(&check.result) {
+                    Ok(_) => {
+                        warn!("Spooled the result of {}/{} until it can be sent.", group, name);
+                        spooled_deletions.push(check.receipt_handle);
+                    },
+                    Err(e) => {
+                        error!("Failed to spool the result of {}/{}, leaving its message on the queue:  {}",
+                               group, name, e);
+                        unspooled_in_flight.remove(&check.receipt_handle);
+                    },
+                }
+            });
+
         Ok(Consumer {
             config,
-            stop: AtomicBool::new(false),
-            command_queue: reg_res.command_queue,
-            result_queue: reg_res.result_queue,
+            clients: None,
+            reregister: None,
+            runtime: Mutex::new(runtime),
+            stop: Arc::new(AtomicBool::new(false)),
+            stopped_at: Mutex::new(None),
+            error: Mutex::new(None),
+            source,
+            slots,
+            in_flight,
+            results: Arc::new(results),
+            deletions,
+            replay,
+            policy,
+            verifier,
+            identities,
         })
     }
 
     /// Start the consumer loop.
     /// The consumer will poll the `command` queue and run the check commands,
     /// sending their responses to the `result` queue.
-    /// Call [stop] on the consumer instance to stop polling and return.
+    /// The `command` queue is only polled while a slot is idle,
+    /// so messages are left in the queue for other clients when `concurrency` checks are running.
+    /// Call [stop] on the consumer instance to stop polling, drain the running checks and return.
+    /// While running, the client registers again every `registration_interval` seconds.
+    /// Failed receives are retried with backoff, and if the command queue no longer exists the client
+    /// registers again.  Errors which retrying will not fix stop the consumer, see [error].
+    ///
+    /// The polling, checks and visibility heartbeat all run as tasks on the consumer's runtime,
+    /// while this thread blocks until they are done.
     pub fn start(&self) {
-        // SQS queue listener.
-        let rcv_req = ReceiveMessageRequest {
-            attribute_names: None,
-            max_number_of_messages: Some(1),
-            message_attribute_names: None,
-            queue_url: self.command_queue.clone(),
-            receive_request_attempt_id: None,  // Only valid for FIFO queues.
-            visibility_timeout: Some(300),
-            wait_time_seconds: Some(20),  // 20 seconds is the maximum.
+        let mut runtime = self.runtime.lock().unwrap();
+        let heartbeat = VisibilityHeartbeat::start(&runtime.executor(), self.source.clone(), self.in_flight.clone());
+        let registration = match self.reregister {
+            Some(ref register) if self.config.registration_interval > 0 => Some(RegistrationHeartbeat::start(
+                Duration::from_secs(self.config.registration_interval), register.clone())),
+            _ => None,
         };
-        let sqs_client = SqsClient::new(self.config.region.clone());
 
         info!("Listening for messages...");
-        while !self.stop.load(Ordering::SeqCst) {
-            // Listen for a message.
-            let rcv_res = sqs_client.receive_message(rcv_req.clone()).sync();
-            match rcv_res {
-                Err(e) => {
-                    error!("Error receiving message:  {:?}", e);
-                    thread::sleep(Duration::from_secs(5));
-                },
-                Ok(sqs_messages) => {
-                    if let Some(messages) = sqs_messages.messages {
-                        for message in messages.iter() {
-                            // Clone values for passing into spawned thread.
-                            let c_message = message.clone();
-                            let c_config = self.config.clone();
-                            let c_command_queue = self.command_queue.clone();
-                            let c_result_queue = self.result_queue.clone();
-                            // Spawn thread to perform check.
-                            thread::spawn(move || {
-                                CheckExecutor
-                                    ::new(c_config, c_command_queue, c_result_queue, c_message)
-                                    .execute();
-                            });
-                        }
-                    }
-                }
-            }
+        let listener = Listener {
+            config: self.config.clone(),
+            source: self.source.clone(),
+            stop: self.stop.clone(),
+            slots: self.slots.clone(),
+            in_flight: self.in_flight.clone(),
+            results: self.results.clone(),
+            deletions: self.deletions.clone(),
+            policy: self.policy.clone(),
+            verifier: self.verifier.clone(),
+            identities: self.identities.clone(),
+            executor: runtime.executor(),
+            reregister: self.reregister.clone(),
+            backoff: Backoff::new(RECEIVE_BACKOFF_BASE, RECEIVE_BACKOFF_MAX),
+            retry_at: None,
+            metrics_logged_at: Instant::now(),
+        };
+        let res = runtime.block_on(future::loop_fn(listener, Listener::next));
+        if let Err(e) = res {
+            error!("Stopped receiving from the command queue:  {}", e);
+            *self.error.lock().unwrap() = Some(e.to_string());
+            self.stop();
         }
 
+        let deadline = self.drain(&mut runtime, &heartbeat);
+        // Stopped before de-registering, so the client is not registered again.
+        let registration_stopped = match registration {
+            Some(registration) => registration.stop_by(deadline),
+            None => true,
+        };
+        info!("Metrics:  {}", metrics::snapshot());
+
         if self.config.auto_deregister {
-            info!("Auto-deregistering client.");
-            match self.deregister() {
-                Ok(_) => info!("Successfully de-registered."),
-                Err(e) => error!("Client de-registration failed: {}", e),
+            if registration_stopped {
+                self.deregister_by(deadline);
+            } else {
+                error!("Registration still in progress at the stop deadline, not de-registering.");
             }
         }
     }
 
+    /// The error which stopped the consumer by itself, rather than [stop] being called, once [start] has returned.
+    pub fn error(&self) -> Option<String> {
+        self.error.lock().unwrap().clone()
+    }
+
     /// Stop the consumer loop.
+    /// The shutdown deadline for running checks is counted from this call.
     pub fn stop(&self) {
         info!("Terminating...");
+        self.stopped_at.lock().unwrap().get_or_insert_with(Instant::now);
         self.stop.store(true, Ordering::SeqCst);
     }
 
-    /// De-register/de-activate the client.
-    fn deregister(&self) -> Result<(), Box<dyn Error>> {
-        // Get de-registration endpoint.
-        let deregistration_arn = ssm::get_registration_arn(&self.config.region, &self.config.deregistration_parameter)?;
-        info!("De-registration ARN:  {}", deregistration_arn);
-        // De-register
-        let dereg_req = deregistration::Request::new(&self.config.client_name);
-        debug!("De-registration request:  {:?}", dereg_req);
-        let dereg_res = dereg_req.execute(&self.config.region, &deregistration_arn)?;
-        if dereg_res.code == 200 {
-            Ok(())
+    /// Wait for the running checks to finish and publish their results until the shutdown deadline,
+    /// then kill the remaining checks and release their messages back to the command queue.
+    /// Returns the deadline of the final steps, which publishing the results is bounded by.
+    fn drain(&self, runtime: &mut Runtime, heartbeat: &VisibilityHeartbeat) -> Instant {
+        let stopped_at = self.stopped_at.lock().unwrap().unwrap_or_else(Instant::now);
+        let deadline = stopped_at + Duration::from_secs(self.config.shutdown_timeout);
+        let final_deadline = deadline + KILL_GRACE_PERIOD + FINAL_STEPS_TIMEOUT;
+        if !self.in_flight.is_empty() {
+            info!("Waiting for {} in-flight check(s) to finish...", self.in_flight.len());
+        }
+        let finished = runtime.block_on(Timeout::new_at(self.slots.wait_for_all_idle(), deadline)).is_ok();
+        heartbeat.stop();
+        if finished {
+            info!("All checks finished.");
         } else {
-            Err(Box::new(
-                RegistrationError { code: dereg_res.code, description: dereg_res.message }
-            ))
+            let aborted = self.in_flight.abort_all();
+            warn!("Shutdown deadline reached, aborted {} check(s).", aborted.len());
+            if runtime.block_on(Timeout::new(self.slots.wait_for_all_idle(), KILL_GRACE_PERIOD)).is_err() {
+                warn!("Checks still running after killing them.");
+            }
+            let receipt_handles = aborted.into_iter()
+                .map(|check| check.receipt_handle)
+                .collect::<Vec<String>>();
+            if let Err(e) = runtime.block_on(self.source.release(receipt_handles)) {
+                error!("Failed to release messages back to the queue:  {}", e);
+            }
+        }
+        // Publish the outstanding results, including any spooled, and deletions.
+        if !self.results.close_by(final_deadline) {
+            error!("Stop deadline reached while sending results.");
+        }
+        if !self.replay.stop_by(final_deadline) {
+            error!("Stop deadline reached while replaying spooled results.");
+        }
+        if !self.deletions.close_by(final_deadline) {
+            error!("Stop deadline reached while deleting messages.");
         }
+        final_deadline
+    }
+
+    /// De-register the client, giving up at the deadline.
+    fn deregister_by(&self, deadline: Instant) {
+        info!("Auto-deregistering client.");
+        let config = self.config.clone();
+        let clients = self.clients.clone();
+        let finished = Worker::spawn("deregistration", move || {
+            match deregister(&config, clients.as_ref()) {
+                Ok(_) => info!("Successfully de-registered."),
+                Err(e) => error!("Client de-registration failed: {}", e),
+            }
+        }).map(|worker| worker.join_by(deadline));
+        match finished {
+            Ok(true) => {},
+            Ok(false) => error!("Stop deadline reached while de-registering."),
+            Err(e) => error!("Client de-registration failed: {}", e),
+        }
+    }
+}
+
+/// De-register/de-activate the client.
+fn deregister(config: &Config, clients: Option<&Clients>) -> Result<(), Box<dyn Error>> {
+    // Get de-registration endpoint.
+    let deregistration = config.deregistration.as_ref()
+        .ok_or("No de-registration function configured.")?;
+    let clients = clients
+        .ok_or("Not registered with the monitoring service.")?;
+    let deregistration_arn = ssm::get_function_arn(&clients.ssm(), deregistration)?;
+    info!("De-registration ARN:  {}", deregistration_arn);
+    // De-register
+    let dereg_req = deregistration::Request::new(&config.client_name);
+    debug!("De-registration request:  {:?}", dereg_req);
+    let dereg_res = dereg_req.execute(&clients.lambda(), &deregistration_arn)?;
+    if dereg_res.code == 200 {
+        Ok(())
+    } else {
+        Err(Box::new(
+            RegistrationError { code: dereg_res.code, description: dereg_res.message }
+        ))
+    }
+}
+
+/// Register the client with the monitoring service, reporting its status and returning its queues.
+fn register(config: &Config, clients: &Clients, uptime_seconds: u64, in_flight: usize)
+            -> Result<registration::Response, Box<dyn Error>>
+{
+    // Get registration endpoint.
+    let registration_arn = ssm::get_function_arn(&clients.ssm(), &config.registration)?;
+    debug!("Registration ARN:  {}", registration_arn);
+
+    // Register
+    let reg_req = registration::Request::new(&config.client_name, &tags::registered(&config.tags), uptime_seconds, in_flight);
+    debug!("Registration request:  {:?}", reg_req);
+    let reg_res = reg_req.execute(&clients.lambda(), &registration_arn)?;
+    debug!("Registration response:  {:?}", reg_res);
+    Ok(reg_res)
+}
+
+/// The state of the command queue receive loop, run on the runtime.
+struct Listener {
+    config: Config,
+    source: Arc<dyn CommandSource>,
+    stop: Arc<AtomicBool>,
+    slots: Slots,
+    in_flight: Arc<InFlight>,
+    results: Arc<Batcher<CompletedCheck>>,
+    deletions: Arc<Batcher<String>>,
+    policy: Option<Arc<Policy>>,
+    verifier: Option<Arc<Verifier>>,
+    identities: Arc<Identities>,
+    executor: TaskExecutor,
+    reregister: Option<Register>,
+    backoff: Backoff,
+    /// When to receive again after a failed receive.
+    retry_at: Option<Instant>,
+    metrics_logged_at: Instant,
+}
+
+type Step = Box<dyn Future<Item = Loop<(), Listener>, Error = ReceiveError> + Send>;
+
+impl Listener {
+    /// Wait for an idle slot and receive as many messages as there are idle slots.
+    fn next(mut self) -> Step {
+        if self.stop.load(Ordering::SeqCst) {
+            return Box::new(future::ok(Loop::Break(())));
+        }
+        if self.metrics_logged_at.elapsed() >= metrics::LOG_INTERVAL {
+            info!("Metrics:  {}", metrics::snapshot());
+            self.metrics_logged_at = Instant::now();
+        }
+        // Back off after a failed receive, waking up regularly to check whether the consumer has been stopped.
+        if let Some(retry_at) = self.retry_at {
+            let now = Instant::now();
+            if retry_at > now {
+                let wake_at = retry_at.min(now + IDLE_POLL_INTERVAL);
+                return Box::new(Delay::new(wake_at).then(move |_| Ok(Loop::Continue(self))));
+            }
+            self.retry_at = None;
+        }
+        // Wait for a free slot before taking messages off the queue.
+        Box::new(Timeout::new(self.slots.wait_for_idle(), IDLE_POLL_INTERVAL).then(move |idle| match idle {
+            Ok(idle) => self.receive(idle),
+            Err(_) => Box::new(future::ok(Loop::Continue(self))),
+        }))
+    }
+
+    /// Receive up to `idle` messages, spawning a check for each.
+    fn receive(mut self, idle: usize) -> Step {
+        // Take no more messages than there are idle slots.
+        let receive = self.source.receive(idle.min(batch::MAX_BATCH_SIZE), Duration::from_secs(RECEIVE_VISIBILITY_TIMEOUT));
+        Box::new(receive.then(move |rcv_res| -> Step {
+            match rcv_res {
+                Err(e) => self.failed(e),
+                Ok(messages) => {
+                    self.backoff.reset();
+                    if self.stop.load(Ordering::SeqCst) && !messages.is_empty() {
+                        // Received while terminating, let another client have them.
+                        let receipt_handles = messages.into_iter()
+                            .map(|m| m.receipt_handle)
+                            .collect::<Vec<String>>();
+                        return Box::new(self.source.release(receipt_handles).then(|res| {
+                            if let Err(e) = res {
+                                error!("Failed to release messages back to the queue:  {}", e);
+                            }
+                            Ok(Loop::Break(()))
+                        }));
+                    }
+                    for message in messages {
+                        self.in_flight.insert(
+                            &message.message_id,
+                            &message.receipt_handle,
+                            Duration::from_secs(RECEIVE_VISIBILITY_TIMEOUT));
+                        // The slot is held until the check completes.
+                        let slot = self.slots.acquire();
+                        let check = CheckExecutor {
+                            config: self.config.clone(),
+                            message,
+                            in_flight: self.in_flight.clone(),
+                            results: self.results.clone(),
+                            deletions: self.deletions.clone(),
+                            policy: self.policy.clone(),
+                            verifier: self.verifier.clone(),
+                            identities: self.identities.clone(),
+                        };
+                        self.executor.spawn(check.execute().then(move |res| {
+                            drop(slot);
+                            res
+                        }));
+                    }
+                    Box::new(future::ok(Loop::Continue(self)))
+                },
+            }
+        }))
+    }
+
+    /// Handle a failed receive by backing off, registering again or, if the error is fatal, stopping.
+    fn failed(mut self, e: ReceiveError) -> Step {
+        match e.kind {
+            ErrorKind::Retryable => error!("Error receiving message:  {}", e),
+            ErrorKind::QueueMissing => {
+                let reregister = match self.reregister {
+                    Some(ref reregister) => reregister.clone(),
+                    None => return Box::new(future::err(e)),
+                };
+                warn!("The command queue no longer exists, registering again:  {}", e);
+                // Registering blocks on its requests, so runs on its own thread rather than a runtime worker.
+                let (sender, registered) = oneshot::channel();
+                let spawned = thread::Builder::new()
+                    .name(String::from("reregistration"))
+                    .spawn(move || {
+                        let _ = sender.send(reregister().map_err(|e| e.to_string()));
+                    });
+                if let Err(e) = spawned {
+                    error!("Failed to spawn registration thread:  {}", e);
+                    return Box::new(future::ok(self.retry_later()));
+                }
+                return Box::new(registered.then(move |res| {
+                    match res {
+                        Ok(Ok(_)) => {
+                            self.backoff.reset();
+                            return Ok(Loop::Continue(self));
+                        },
+                        Ok(Err(e)) => error!("Failed to register again:  {}", e),
+                        Err(_) => error!("Registration thread terminated abnormally."),
+                    }
+                    Ok(self.retry_later())
+                }));
+            },
+            ErrorKind::Fatal => return Box::new(future::err(e)),
+        }
+        Box::new(future::ok(self.retry_later()))
+    }
+
+    /// Back off before receiving again.
+    fn retry_later(mut self) -> Loop<(), Listener> {
+        let delay = self.backoff.next_delay();
+        info!("Receiving again in {} ms, after {} consecutive failure(s).", delay.as_millis(), self.backoff.attempts());
+        self.retry_at = Some(Instant::now() + delay);
+        Loop::Continue(self)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::atomic::AtomicUsize;
+
+    use chrono::Utc;
+
+    use crate::config::settings::Settings;
+    use crate::messages::check::CheckResultStatus;
+    use crate::transport::memory::MemoryTransport;
+
+    /// The configuration, with a fresh spool directory for the test.
+    /// Checks run as whoever runs the tests, even root.
+    fn config(test: &str, concurrency: usize, shutdown_timeout: u64) -> Config {
+        let spool_dir = env::temp_dir().join(format!("smdf-consumer-{}-{}", process::id(), test));
+        let _ = fs::remove_dir_all(&spool_dir);
+        Config::from_settings(Settings {
+            name: Some(String::from("test-client")),
+            tags: Some(vec![String::from("test")]),
+            region: Some(String::from("us-east-1")),
+            reg_arn: Some(String::from("arn:aws:lambda:us-east-1:123456789012:function:register")),
+            concurrency: Some(concurrency),
+            shutdown_timeout: Some(shutdown_timeout),
+            spool_dir: Some(spool_dir.to_string_lossy().to_string()),
+            allow_root: Some(true),
+            ..Settings::default()
+        }).unwrap()
+    }
+
+    fn check(name: &str, command: &str) -> String {
+        format!("{{\"scheduledAt\":\"2019-01-10T11:07:44Z\",\"group\":\"test\",\"name\":\"{}\",\"command\":\"{}\",\"timeout\":30,\"tags\":[\"test\"]}}",
+                name, command)
+    }
+
+    fn consumer(config: Config, transport: &Arc<MemoryTransport>) -> Consumer {
+        Consumer::with_transport(config, transport.clone(), transport.clone()).unwrap()
+    }
+
+    /// Run the consumer until `done`, it stops by itself or a time-out, then stop it and wait for it to drain.
+    fn run_until<F>(consumer: Consumer, transport: &Arc<MemoryTransport>, done: F) -> Result<(), String>
+        where F: Fn(&MemoryTransport) -> bool
+    {
+        let consumer = Arc::new(consumer);
+        let c_consumer = consumer.clone();
+        let handle = thread::spawn(move || {
+            c_consumer.start();
+            c_consumer.error().map_or(Ok(()), Err)
+        });
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while !done(transport) && !handle.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        consumer.stop();
+        handle.join().unwrap()
+    }
+
+    #[test]
+    fn runs_checks_and_deletes_messages() {
+        let transport = Arc::new(MemoryTransport::new());
+        let message_ids: HashSet<String> = vec![
+            transport.push(&check("ok", "echo ok")),
+            transport.push(&check("critical", "echo critical && exit 2")),
+            transport.push(&check("unknown", "exit 3")),
+        ].into_iter().collect();
+
+        run_until(consumer(config("runs", 2, 5), &transport), &transport, |t| t.deleted().len() == 3).unwrap();
+
+        let results = transport.results();
+        assert_eq!(3, results.len());
+        let status = |name: &str| results.iter().find(|r| r.name == name).map(|r| r.status.clone());
+        assert_eq!(Some(CheckResultStatus::OK), status("ok"));
+        assert_eq!(Some(CheckResultStatus::CRITICAL), status("critical"));
+        assert_eq!(Some(CheckResultStatus::UNKNOWN), status("unknown"));
+        assert!(results.iter().all(|r| r.source == "test-client"));
+        assert_eq!(message_ids, transport.deleted().into_iter().collect());
+        assert_eq!(0, transport.queued());
+        assert_eq!(0, transport.in_flight());
+    }
+
+    #[test]
+    fn duplicate_deliveries_are_tracked_separately() {
+        let transport = Arc::new(MemoryTransport::new());
+        let message_id = transport.push(&check("slow", "sleep 0.5 && echo ok"));
+        let redelivered = AtomicBool::new(false);
+
+        run_until(consumer(config("duplicate", 2, 5), &transport), &transport, |t| {
+            if t.in_flight() == 1 && !redelivered.swap(true, Ordering::SeqCst) {
+                t.redeliver(&message_id);
+            }
+            t.deleted().len() == 2
+        }).unwrap();
+
+        // Each delivery ran, rather than the second replacing the first and its result being discarded.
+        let results = transport.results();
+        assert_eq!(2, results.len());
+        assert!(results.iter().all(|r| r.status == CheckResultStatus::OK));
+        assert_eq!(vec![message_id.clone(), message_id], transport.deleted());
+        assert_eq!(0, transport.in_flight());
+    }
+
+    #[test]
+    fn messages_stay_in_flight_until_deleted() {
+        let transport = Arc::new(MemoryTransport::new());
+        transport.push(&check("slow-send", "echo ok"));
+        transport.delay_sends(Duration::from_millis(500));
+        let consumer = consumer(config("slow-send", 1, 5), &transport);
+        let in_flight = consumer.in_flight.clone();
+        let tracked = AtomicBool::new(false);
+        let untracked_before_delete = AtomicBool::new(false);
+
+        run_until(consumer, &transport, |t| {
+            if !in_flight.is_empty() {
+                tracked.store(true, Ordering::SeqCst);
+            } else if tracked.load(Ordering::SeqCst) && t.deleted().is_empty() {
+                untracked_before_delete.store(true, Ordering::SeqCst);
+            }
+            t.deleted().len() == 1
+        }).unwrap();
+
+        // Still tracked, so its visibility extended, while the result was being sent.
+        assert!(tracked.load(Ordering::SeqCst));
+        assert!(!untracked_before_delete.load(Ordering::SeqCst));
+        assert_eq!(1, transport.results().len());
+        assert!(in_flight.is_empty());
+    }
+
+    #[test]
+    fn failed_sends_are_retried() {
+        let transport = Arc::new(MemoryTransport::new());
+        transport.fail_sends(2);
+        transport.push(&check("ok", "echo ok"));
+
+        run_until(consumer(config("sends", 1, 5), &transport), &transport, |t| t.results().len() == 1 && t.deleted().len() == 1).unwrap();
+
+        assert_eq!(1, transport.results().len());
+        assert_eq!(1, transport.deleted().len());
+    }
+
+    #[test]
+    fn failed_deletes_are_retried() {
+        let transport = Arc::new(MemoryTransport::new());
+        transport.fail_deletes(1);
+        let message_id = transport.push(&check("ok", "echo ok"));
+
+        run_until(consumer(config("deletes", 1, 5), &transport), &transport, |t| t.deleted().len() == 1).unwrap();
+
+        assert_eq!(vec![message_id], transport.deleted());
+        assert_eq!(0, transport.in_flight());
+    }
+
+    #[test]
+    fn failed_receives_are_retried() {
+        let transport = Arc::new(MemoryTransport::new());
+        transport.fail_receives(1);
+        transport.push(&check("ok", "echo ok"));
+
+        run_until(consumer(config("receives", 1, 5), &transport), &transport, |t| t.deleted().len() == 1).unwrap();
+
+        assert_eq!(1, transport.results().len());
+        assert_eq!(1, transport.deleted().len());
+    }
+
+    #[test]
+    fn missing_queue_registers_again() {
+        let transport = Arc::new(MemoryTransport::new());
+        transport.fail_receives_with(ErrorKind::QueueMissing, 1);
+        transport.push(&check("ok", "echo ok"));
+        let registrations = Arc::new(AtomicUsize::new(0));
+        let threads = Arc::new(Mutex::new(Vec::new()));
+        let mut consumer = consumer(config("reregister", 1, 5), &transport);
+        let (c_registrations, c_threads) = (registrations.clone(), threads.clone());
+        consumer.reregister = Some(Arc::new(move || {
+            c_registrations.fetch_add(1, Ordering::SeqCst);
+            c_threads.lock().unwrap().push(thread::current().name().map(String::from));
+            Ok(())
+        }));
+
+        run_until(consumer, &transport, |t| t.deleted().len() == 1).unwrap();
+
+        assert_eq!(1, registrations.load(Ordering::SeqCst));
+        assert_eq!(1, transport.deleted().len());
+        // Not on a runtime worker.
+        assert_eq!(vec![Some(String::from("reregistration"))], *threads.lock().unwrap());
+    }
+
+    #[test]
+    fn failed_reregistration_backs_off() {
+        let transport = Arc::new(MemoryTransport::new());
+        transport.fail_receives_with(ErrorKind::QueueMissing, 2);
+        transport.push(&check("ok", "echo ok"));
+        let registrations = Arc::new(AtomicUsize::new(0));
+        let mut consumer = consumer(config("reregister-failed", 1, 5), &transport);
+        let c_registrations = registrations.clone();
+        consumer.reregister = Some(Arc::new(move || {
+            c_registrations.fetch_add(1, Ordering::SeqCst);
+            Err("Registration failed.".into())
+        }));
+        let started_at = Instant::now();
+
+        run_until(consumer, &transport, |t| t.deleted().len() == 1).unwrap();
+
+        // Each failure backs off and receives again, rather than stopping the consumer.
+        assert_eq!(2, registrations.load(Ordering::SeqCst));
+        assert_eq!(1, transport.deleted().len());
+        assert!(started_at.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn fatal_receive_errors_stop_the_consumer() {
+        let transport = Arc::new(MemoryTransport::new());
+        transport.fail_receives_with(ErrorKind::Fatal, 1);
+        transport.push(&check("ok", "echo ok"));
+
+        let res = run_until(consumer(config("fatal", 1, 5), &transport), &transport, |_| false);
+
+        assert!(res.unwrap_err().contains("Fatal"));
+        assert!(transport.results().is_empty());
+        assert_eq!(1, transport.queued());
+    }
+
+    #[test]
+    fn rejected_results_are_replaced() {
+        let transport = Arc::new(MemoryTransport::new());
+        let message_id = transport.push(&check("rejected", "echo rejected"));
+        transport.reject_results(1);
+
+        run_until(consumer(config("replaced", 1, 5), &transport), &transport, |t| t.deleted().len() == 1).unwrap();
+
+        let results = transport.results();
+        assert_eq!(1, results.len());
+        assert_eq!(CheckResultStatus::UNKNOWN, results[0].status);
+        assert_eq!("Result rejected by the result queue:  InvalidMessageContents Invalid result.", results[0].output);
+        assert_eq!(vec![message_id], transport.deleted());
+    }
+
+    #[test]
+    fn unsendable_results_leave_their_message() {
+        let transport = Arc::new(MemoryTransport::new());
+        transport.push(&check("rejected", "echo rejected"));
+        transport.reject_results(2);
+
+        // Once received, the check completes and its result is flushed while draining.
+        run_until(consumer(config("unsendable", 1, 5), &transport), &transport, |t| t.in_flight() == 1).unwrap();
+
+        assert!(transport.results().is_empty());
+        assert!(transport.deleted().is_empty());
+        assert_eq!(1, transport.in_flight());
+    }
+
+    #[test]
+    fn checks_with_other_tags_are_rejected() {
+        let transport = Arc::new(MemoryTransport::new());
+        let marker = env::temp_dir().join(format!("smdf-consumer-{}-rejected-ran", process::id()));
+        let command = format!("touch {}", marker.display());
+        transport.push(&check("other", &command).replace("[\"test\"]", "[\"other\"]"));
+        transport.push(&check("excluded", &command).replace("[\"test\"]", "[\"test\",\"test-staging\"]"));
+        transport.push(&check("untagged", &command).replace("[\"test\"]", "[]"));
+        let mut config = config("rejected", 1, 5);
+        config.exclude_tags = vec![String::from("*-staging")];
+
+        run_until(consumer(config, &transport), &transport, |t| t.deleted().len() == 3).unwrap();
+
+        assert!(!marker.exists());
+        let results = transport.results();
+        assert_eq!(3, results.len());
+        assert!(results.iter().all(|r| r.status == CheckResultStatus::UNKNOWN));
+        assert!(results.iter().all(|r| r.output.starts_with("Check rejected by client")));
+    }
+
+    #[test]
+    fn expired_checks_are_skipped() {
+        let transport = Arc::new(MemoryTransport::new());
+        let expired_id = transport.push(&check("expired", "echo expired"));
+        let current = check("current", "echo current")
+            .replace("2019-01-10T11:07:44Z", &Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string());
+        transport.push(&current);
+        let mut config = config("expired", 1, 5);
+        config.max_check_age = 3600;
+
+        run_until(consumer(config, &transport), &transport, |t| t.deleted().len() == 2).unwrap();
+
+        let results = transport.results();
+        assert_eq!(1, results.len());
+        assert_eq!("current", results[0].name);
+        assert!(transport.deleted().contains(&expired_id));
+    }
+
+    #[test]
+    fn expired_checks_are_reported() {
+        let transport = Arc::new(MemoryTransport::new());
+        transport.push(&check("expired", "echo expired"));
+        let mut config = config("reported", 1, 5);
+        config.max_check_age = 3600;
+        config.report_expired = true;
+
+        run_until(consumer(config, &transport), &transport, |t| t.deleted().len() == 1).unwrap();
+
+        let results = transport.results();
+        assert_eq!(1, results.len());
+        assert_eq!(CheckResultStatus::UNKNOWN, results[0].status);
+        assert!(results[0].output.starts_with("Check expired without running"));
+    }
+
+    #[test]
+    fn checks_are_not_run_as_root() {
+        let transport = Arc::new(MemoryTransport::new());
+        let marker = env::temp_dir().join(format!("smdf-consumer-{}-root-ran", process::id()));
+        let command = format!("touch {}", marker.display());
+        transport.push(&check("client-root", &command));
+        transport.push(&check("check-root", &command).replace("}", ",\"runAs\":{\"user\":\"0\"}}"));
+        let mut config = config("root", 1, 5);
+        config.allow_root = false;
+        config.run_as.user = Some(String::from("root"));
+
+        run_until(consumer(config, &transport), &transport, |t| t.deleted().len() == 2).unwrap();
+
+        assert!(!marker.exists());
+        let results = transport.results();
+        assert_eq!(2, results.len());
+        assert!(results.iter().all(|r| r.output.starts_with("Check denied by client")));
+    }
+
+    #[test]
+    fn unverified_messages_are_discarded() {
+        use ring::hmac;
+        use crate::signature::Envelope;
+
+        const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+        let keys = env::temp_dir().join(format!("smdf-consumer-{}-keys.toml", process::id()));
+        fs::write(&keys, format!("[[key]]\nid = \"test\"\nalgorithm = \"hmac-sha256\"\nsecret = \"{}\"\n",
+                                 base64::encode(SECRET))).unwrap();
+        let key = hmac::Key::new(hmac::HMAC_SHA256, SECRET);
+        let sign = |payload: &str, signed: &str| serde_json::to_string(&Envelope {
+            payload: payload.to_string(),
+            key_id: String::from("test"),
+            signature: base64::encode(hmac::sign(&key, signed.as_bytes()).as_ref()),
+        }).unwrap();
+        let now = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+        let signed = check("signed", "echo signed").replace("2019-01-10T11:07:44Z", &now);
+        let tampered = signed.replace("signed", "tampered");
+        let transport = Arc::new(MemoryTransport::new());
+        transport.push(&sign(&signed, &signed));
+        // Replayed within the window.
+        transport.push(&sign(&signed, &signed));
+        transport.push(&sign(&tampered, &signed));
+        transport.push(&check("unsigned", "echo unsigned").replace("2019-01-10T11:07:44Z", &now));
+        // Validly signed, but outside the replay window.
+        let old = check("old", "echo old");
+        transport.push(&sign(&old, &old));
+        transport.push("not json");
+        let mut config = config("signed", 1, 5);
+        config.signing_keys = Some(keys.to_string_lossy().to_string());
+
+        let res = run_until(consumer(config, &transport), &transport, |t| t.deleted().len() == 6);
+        fs::remove_file(&keys).unwrap();
+        res.unwrap();
+
+        let mut results = transport.results();
+        results.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(2, results.len());
+        assert_eq!("old", results[0].name);
+        assert!(results[0].output.starts_with("Check rejected by client:  Scheduled at"));
+        assert_eq!("signed", results[1].name);
+        assert_eq!("signed\n", results[1].output);
+    }
+
+    #[test]
+    fn checks_running_past_the_shutdown_deadline_are_released() {
+        let transport = Arc::new(MemoryTransport::new());
+        transport.push(&check("slow", "sleep 30"));
+
+        run_until(consumer(config("released", 1, 0), &transport), &transport, |t| {
+            // Give the check time to start before stopping.
+            let started = t.in_flight() == 1;
+            if started {
+                thread::sleep(Duration::from_millis(200));
+            }
+            started
+        }).unwrap();
+
+        assert!(transport.results().is_empty());
+        assert!(transport.deleted().is_empty());
+        assert_eq!(1, transport.queued());
+        assert_eq!(0, transport.in_flight());
+    }
+
+    #[test]
+    fn unsent_results_are_spooled_and_replayed() {
+        let transport = Arc::new(MemoryTransport::new());
+        // Fail every attempt of the first batch.
+        transport.fail_sends(3);
+        let message_id = transport.push(&check("ok", "echo ok"));
+        let config = config("spooled", 1, 5);
+        let spool_dir = config.spool.dir.clone();
+
+        // The message is deleted once the result is spooled, before it has been sent.
+        run_until(consumer(config, &transport), &transport, |t| t.deleted().len() == 1).unwrap();
+
+        assert_eq!(vec![message_id], transport.deleted());
+        // Replayed on shutdown.
+        assert_eq!(1, transport.results().len());
+        assert_eq!(0, fs::read_dir(&spool_dir).unwrap().count());
     }
 }
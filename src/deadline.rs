@@ -0,0 +1,71 @@
+//! Background threads which can be waited on until a deadline.
+//! The service manager kills the client if it takes too long to stop, so the shutdown steps
+//! wait for their threads until an overall deadline rather than indefinitely.
+
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Instant;
+
+use log::error;
+
+
+/// A named thread, which signals its end by dropping its side of a channel.
+pub struct Worker {
+    name: String,
+    handle: thread::JoinHandle<()>,
+    done: mpsc::Receiver<()>,
+}
+
+impl Worker {
+    pub fn spawn<F>(name: &str, f: F) -> io::Result<Self>
+        where F: FnOnce() + Send + 'static
+    {
+        let (done_sender, done) = mpsc::channel::<()>();
+        let handle = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                let _done = done_sender;
+                f();
+            })?;
+        Ok(Self { name: name.to_string(), handle, done })
+    }
+
+    /// Wait for the thread to finish.
+    pub fn join(self) {
+        if self.handle.join().is_err() {
+            error!("The {} thread terminated abnormally.", self.name);
+        }
+    }
+
+    /// Wait for the thread to finish until the deadline, returning whether it did.
+    /// A thread still running is left to be ended with the process.
+    pub fn join_by(self, deadline: Instant) -> bool {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match self.done.recv_timeout(timeout) {
+            Ok(_) | Err(RecvTimeoutError::Disconnected) => {
+                self.join();
+                true
+            },
+            Err(RecvTimeoutError::Timeout) => false,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn joins_until_the_deadline() {
+        let worker = Worker::spawn("test-quick", || {}).unwrap();
+        assert!(worker.join_by(Instant::now() + Duration::from_secs(5)));
+
+        let started_at = Instant::now();
+        let worker = Worker::spawn("test-slow", || thread::sleep(Duration::from_secs(2))).unwrap();
+        assert!(!worker.join_by(Instant::now() + Duration::from_millis(100)));
+        assert!(started_at.elapsed() < Duration::from_secs(1));
+    }
+}
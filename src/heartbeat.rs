@@ -0,0 +1,79 @@
+//! Periodic re-registration with the monitoring service.
+//! Each registration reports the client's status, and may give the client new queues,
+//! eg. when the monitoring service has lost the client's record or rotated its queues.
+
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use log::{debug, error};
+
+use crate::deadline::Worker;
+
+
+/// Registers the client, pointing the transport at the queues it is given.
+pub type Register = Arc<dyn Fn() -> Result<(), Box<dyn Error>> + Send + Sync>;
+
+/// Registers on a background thread every interval.
+pub struct RegistrationHeartbeat {
+    stop: Mutex<Option<mpsc::Sender<()>>>,
+    worker: Mutex<Option<Worker>>,
+}
+
+impl RegistrationHeartbeat {
+    /// Start registering, the first time after `interval`.
+    pub fn start(interval: Duration, register: Register) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let worker = Worker::spawn("registration", move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                match register() {
+                    Ok(_) => debug!("Registered again."),
+                    Err(e) => error!("Failed to register again:  {}", e),
+                }
+            }
+        }).expect("Failed to spawn registration thread.");
+        Self {
+            stop: Mutex::new(Some(stop)),
+            worker: Mutex::new(Some(worker)),
+        }
+    }
+
+    /// Stop registering, waiting for any registration in progress until the deadline at most.
+    /// Returns whether the registration thread finished.
+    pub fn stop_by(&self, deadline: Instant) -> bool {
+        self.stop.lock().unwrap().take();
+        match self.worker.lock().unwrap().take() {
+            Some(worker) => worker.join_by(deadline),
+            None => true,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn registers_until_stopped() {
+        let registrations = Arc::new(AtomicUsize::new(0));
+        let c_registrations = registrations.clone();
+        let heartbeat = RegistrationHeartbeat::start(Duration::from_millis(50), Arc::new(move || {
+            // Failures are retried at the next interval.
+            if c_registrations.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Err("Registration failed.".into());
+            }
+            Ok(())
+        }));
+        thread::sleep(Duration::from_millis(400));
+        assert!(heartbeat.stop_by(Instant::now() + Duration::from_secs(5)));
+        let count = registrations.load(Ordering::SeqCst);
+        assert!(count >= 3);
+
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(count, registrations.load(Ordering::SeqCst));
+    }
+}
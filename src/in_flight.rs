@@ -0,0 +1,141 @@
+//! Book-keeping of the command messages which have been taken off the queue
+//! but not yet deleted, so that their visibility can be extended while they run
+//! and their results are sent, and they can be drained on termination.
+//! Messages are tracked by receipt handle, as a message delivered twice is received
+//! with the same ID but a new receipt handle, and each delivery is processed separately.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::timeout;
+
+
+/// A command message being processed by a worker.
+#[derive(Clone, Debug)]
+pub struct InFlightCheck {
+    pub message_id: String,
+    pub receipt_handle: String,
+    /// The process group ID of the running check command, once spawned.
+    pub pid: Option<u32>,
+    /// When the message becomes visible on the queue again.
+    pub visible_until: Instant,
+    /// When the check started running, along with its time-out.
+    pub started: Option<(Instant, Duration)>,
+    /// Whether the check has completed, and its message is waiting for its result to be sent and to be deleted.
+    pub completed: bool,
+}
+
+#[derive(Default)]
+pub struct InFlight {
+    /// The checks by receipt handle.
+    checks: Mutex<HashMap<String, InFlightCheck>>,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Track a message which has been received from the command queue
+    /// with the given visibility time-out.
+    pub fn insert(&self, message_id: &str, receipt_handle: &str, visibility_timeout: Duration) {
+        self.checks.lock().unwrap().insert(receipt_handle.to_string(), InFlightCheck {
+            message_id: message_id.to_string(),
+            receipt_handle: receipt_handle.to_string(),
+            pid: None,
+            visible_until: Instant::now() + visibility_timeout,
+            started: None,
+            completed: false,
+        });
+    }
+
+    /// Whether the message is still tracked, ie. it has not been aborted.
+    pub fn contains(&self, receipt_handle: &str) -> bool {
+        self.checks.lock().unwrap().contains_key(receipt_handle)
+    }
+
+    /// Record that the check for the message has started running with the given time-out.
+    pub fn set_started(&self, receipt_handle: &str, timeout: Duration) {
+        if let Some(check) = self.checks.lock().unwrap().get_mut(receipt_handle) {
+            check.started = Some((Instant::now(), timeout));
+        }
+    }
+
+    /// Record that the message's visibility time-out has been extended.
+    pub fn set_visible_until(&self, receipt_handle: &str, visible_until: Instant) {
+        if let Some(check) = self.checks.lock().unwrap().get_mut(receipt_handle) {
+            check.visible_until = visible_until;
+        }
+    }
+
+    /// The checks whose messages will become visible on the queue again before `deadline`.
+    pub fn expiring_before(&self, deadline: Instant) -> Vec<InFlightCheck> {
+        self.checks.lock().unwrap()
+            .values()
+            .filter(|check| check.visible_until <= deadline)
+            .cloned()
+            .collect()
+    }
+
+    /// Record the process group ID of the check command run for the message.
+    pub fn set_pid(&self, receipt_handle: &str, pid: u32) {
+        if let Some(check) = self.checks.lock().unwrap().get_mut(receipt_handle) {
+            check.pid = Some(pid);
+        }
+    }
+
+    /// Record that the message's check has completed, or was skipped, and it is waiting to be deleted.
+    /// The message stays tracked, so its visibility is still extended, until [remove] is called.
+    /// Returns `false` if the check was aborted in the meantime,
+    /// in which case its result must be discarded and the message left on the queue.
+    pub fn complete(&self, receipt_handle: &str) -> bool {
+        match self.checks.lock().unwrap().get_mut(receipt_handle) {
+            Some(check) => {
+                check.completed = true;
+                // Only the time to send the result and delete the message remains.
+                check.started = None;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Stop tracking the message, once it has been deleted or is being left on the queue.
+    pub fn remove(&self, receipt_handle: &str) {
+        self.checks.lock().unwrap().remove(receipt_handle);
+    }
+
+    /// The number of messages currently being processed.
+    pub fn len(&self) -> usize {
+        self.checks.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Abort every tracked check which has not completed, killing any running check commands.
+    /// Returns the aborted checks so their messages can be released back to the queue.
+    /// The completed checks stay tracked until their messages are deleted.
+    pub fn abort_all(&self) -> Vec<InFlightCheck> {
+        let mut checks = self.checks.lock().unwrap();
+        let receipt_handles: Vec<String> = checks.values()
+            .filter(|check| !check.completed)
+            .map(|check| check.receipt_handle.clone())
+            .collect();
+        let aborted: Vec<InFlightCheck> = receipt_handles.iter()
+            .filter_map(|receipt_handle| checks.remove(receipt_handle))
+            .collect();
+        drop(checks);
+        for check in aborted.iter() {
+            if let Some(pid) = check.pid {
+                debug!("Killing check process group {} for message {}", pid, check.message_id);
+                timeout::signal_group(pid, libc::SIGKILL);
+            }
+        }
+        aborted
+    }
+}
@@ -1,10 +1,24 @@
 #[macro_use]
 extern crate serde_derive;
 
-
 pub mod aws;
+pub mod backoff;
+pub mod batch;
 pub mod config;
+pub mod deadline;
+pub mod heartbeat;
 pub mod messages;
 pub mod consumer;
 pub mod check_executor;
+pub mod in_flight;
+pub mod metrics;
+pub mod policy;
+pub mod privileges;
+pub mod signature;
+pub mod slots;
+pub mod spool;
+pub mod tags;
 pub mod timeout;
+pub mod transport;
+pub mod visibility;
+pub mod wildcard;
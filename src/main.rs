@@ -6,6 +6,7 @@
 //! 1. Execute the specified command.
 //! 1. Return the result of the command to the result queue.
 
+use std::process;
 use std::sync::Arc;
 
 use simplelog::SimpleLogger;
@@ -36,5 +37,9 @@ fn main() {
     }).expect("Error setting the SIGINT/SIGTERM handler.");
 
     consumer.start();
+    if let Some(e) = consumer.error() {
+        error!("Client stopped:  {}", e);
+        process::exit(1);
+    }
 }
 
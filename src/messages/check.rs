@@ -1,33 +1,162 @@
 use chrono::{DateTime, Utc};
 
+use super::perfdata::Metric;
+use crate::privileges::RunAs;
 
-#[derive(Debug, Deserialize)]
+
+/// The maximum size of an SQS message body.
+pub const MAX_MESSAGE_SIZE: usize = 262_144;
+
+#[derive(Clone, Debug, Deserialize)]
 pub struct ClientCheckMessage {
-    #[serde(rename = "scheduledAt")]
+    #[serde(rename = "scheduledAt", with = "super::timestamp")]
     pub scheduled_at: DateTime<Utc>,
     pub group: String,
     pub name: String,
     pub command: String,
     pub timeout: usize,
     pub tags: Vec<String>,
+    #[serde(default)]
+    pub capture: OutputCapture,
+    /// Skip the check if it is picked up more than this many seconds after `scheduledAt`,
+    /// overriding the client's maximum age.  0 runs it however old.
+    #[serde(rename = "maxAge", default)]
+    pub max_age: Option<u64>,
+    /// The user and group to run the command as, overriding the client's.
+    #[serde(rename = "runAs", default)]
+    pub run_as: RunAs,
+}
+
+/// How the output streams of the check command are captured.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputCapture {
+    /// `stdout` as the result output and `stderr` in its own field.
+    #[default]
+    Separate,
+    /// `stdout` and `stderr` interleaved in the order they were written.
+    Merged,
+    /// `stdout` only, discarding `stderr`.
+    Stdout,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ClientCheckResultMessage {
-    #[serde(rename = "completedAt")]
+    #[serde(rename = "completedAt", with = "super::timestamp")]
     pub completed_at: DateTime<Utc>,
-    #[serde(rename = "scheduledAt")]
+    #[serde(rename = "scheduledAt", with = "super::timestamp")]
     pub scheduled_at: DateTime<Utc>,
-    #[serde(rename = "executedAt")]
+    #[serde(rename = "executedAt", with = "super::timestamp")]
     pub executed_at: DateTime<Utc>,
+    /// The time from `executedAt` to `completedAt`.
+    #[serde(rename = "durationMs")]
+    pub duration_ms: i64,
     pub group: String,
     pub name: String,
     pub source: String,
     pub status: CheckResultStatus,
     pub output: String,
+    /// The size of `output` before any truncation.
+    #[serde(rename = "outputBytes")]
+    pub output_bytes: usize,
+    /// Only captured with [OutputCapture::Separate].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+    #[serde(rename = "stderrBytes", default, skip_serializing_if = "Option::is_none")]
+    pub stderr_bytes: Option<usize>,
+    /// Performance data reported by the check.
+    pub metrics: Vec<Metric>,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+impl ClientCheckResultMessage {
+    /// The result of running the check, completed now.
+    pub fn new(check: &ClientCheckMessage, source: &str, executed_at: DateTime<Utc>,
+               status: CheckResultStatus, output: String) -> Self {
+        let completed_at = Utc::now();
+        Self {
+            completed_at,
+            scheduled_at: check.scheduled_at,
+            executed_at,
+            duration_ms: completed_at.signed_duration_since(executed_at).num_milliseconds(),
+            group: check.group.clone(),
+            name: check.name.clone(),
+            source: String::from(source),
+            status,
+            output_bytes: output.len(),
+            output,
+            stderr: None,
+            stderr_bytes: None,
+            metrics: Vec::new(),
+        }
+    }
+
+    /// An `UNKNOWN` result in place of this one, with only the reason as its output,
+    /// for when the result queue rejects this one.
+    pub fn replacement(&self, reason: String) -> Self {
+        Self {
+            status: CheckResultStatus::UNKNOWN,
+            output_bytes: reason.len(),
+            output: reason,
+            stderr: None,
+            stderr_bytes: None,
+            metrics: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Serialize to JSON of at most `max_bytes`, cutting down the largest of
+    /// `output`, `stderr` and `metrics` until the message fits.
+    /// Metrics are dropped from the end, whole.
+    pub fn to_json(&self, max_bytes: usize) -> serde_json::Result<String> {
+        let mut message = self.clone();
+        let stderr = self.stderr.clone().unwrap_or_default();
+        let stderr_bytes = self.stderr_bytes.unwrap_or(stderr.len());
+        let (mut output_len, mut stderr_len) = (self.output.len(), stderr.len());
+        loop {
+            let json = serde_json::to_string(&message)?;
+            let metrics_len = if message.metrics.is_empty() {
+                0
+            } else {
+                serde_json::to_string(&message.metrics)?.len()
+            };
+            if json.len() <= max_bytes || (output_len == 0 && stderr_len == 0 && metrics_len == 0) {
+                return Ok(json);
+            }
+            let excess = json.len() - max_bytes + TRUNCATION_MARKER_SIZE;
+            if metrics_len > output_len && metrics_len > stderr_len {
+                let mut dropped = 0;
+                while dropped < excess {
+                    match message.metrics.pop() {
+                        // Along with the separating comma.
+                        Some(metric) => dropped += serde_json::to_string(&metric)?.len() + 1,
+                        None => break,
+                    }
+                }
+            } else if stderr_len > output_len {
+                stderr_len = stderr_len.saturating_sub(excess);
+                message.stderr = Some(truncate(&stderr, stderr_len, stderr_bytes));
+            } else {
+                output_len = output_len.saturating_sub(excess);
+                message.output = truncate(&self.output, output_len, self.output_bytes);
+            }
+        }
+    }
+}
+
+/// Upper bound of the size of the marker appended by [truncate].
+const TRUNCATION_MARKER_SIZE: usize = 64;
+
+/// Cut the text down to at most `max_len` bytes, on a character boundary,
+/// and append a marker noting how much of the `original_len` bytes were kept.
+pub fn truncate(text: &str, max_len: usize, original_len: usize) -> String {
+    let mut end = max_len.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}\n[... truncated, {} of {} bytes shown]", &text[..end], end, original_len)
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum CheckResultStatus {
     OK,
     WARNING,
@@ -45,3 +174,139 @@ impl CheckResultStatus {
         }
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn result_message(output: String, stderr: Option<String>) -> ClientCheckResultMessage {
+        let check = ClientCheckMessage {
+            scheduled_at: "2019-01-10T11:07:44Z".parse::<DateTime<Utc>>().unwrap(),
+            group: String::from("test"),
+            name: String::from("large-check"),
+            command: String::from("true"),
+            timeout: 30,
+            tags: vec![],
+            capture: OutputCapture::Separate,
+            max_age: None,
+            run_as: RunAs::default(),
+        };
+        let mut message = ClientCheckResultMessage::new(&check, "test-client", Utc::now(), CheckResultStatus::OK, output);
+        message.stderr = stderr;
+        message
+    }
+
+    #[test]
+    fn small_message_is_not_truncated() {
+        let message = result_message(String::from("Ok check\n"), None);
+        let json = message.to_json(MAX_MESSAGE_SIZE).unwrap();
+        assert_eq!(serde_json::to_string(&message).unwrap(), json);
+    }
+
+    #[test]
+    fn large_output_is_truncated() {
+        let message = result_message("x".repeat(300_000), None);
+        let json = message.to_json(MAX_MESSAGE_SIZE).unwrap();
+        assert!(json.len() <= MAX_MESSAGE_SIZE);
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert!(value["output"].as_str().unwrap().contains("[... truncated"));
+        assert_eq!(300_000, value["outputBytes"].as_u64().unwrap());
+    }
+
+    /// The marker gives the command's output size, which capturing may already have cut down.
+    #[test]
+    fn truncation_marker_gives_original_size() {
+        let mut message = result_message("x".repeat(300_000), Some("e".repeat(300_000)));
+        message.output_bytes = 1_000_000;
+        message.stderr_bytes = Some(2_000_000);
+        let json = message.to_json(MAX_MESSAGE_SIZE).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert!(value["output"].as_str().unwrap().ends_with(" of 1000000 bytes shown]"));
+        assert!(value["stderr"].as_str().unwrap().ends_with(" of 2000000 bytes shown]"));
+    }
+
+    #[test]
+    fn perfdata_heavy_message_is_cut_down() {
+        let mut message = result_message(String::from("Ok check\n"), None);
+        message.metrics = (0..10_000)
+            .map(|i| Metric {
+                label: format!("disk-{}", i),
+                value: Some(i as f64),
+                uom: Some(String::from("B")),
+                warn: Some(String::from("80")),
+                crit: Some(String::from("90")),
+                min: Some(0.0),
+                max: Some(100.0),
+            })
+            .collect();
+        assert!(serde_json::to_string(&message).unwrap().len() > MAX_MESSAGE_SIZE);
+
+        let json = message.to_json(MAX_MESSAGE_SIZE).unwrap();
+        assert!(json.len() <= MAX_MESSAGE_SIZE);
+        let parsed: ClientCheckResultMessage = serde_json::from_str(&json).unwrap();
+        // The output is kept, and the metrics dropped from the end only as far as needed.
+        assert_eq!("Ok check\n", parsed.output);
+        assert!(parsed.metrics.len() < 10_000);
+        assert_eq!(&message.metrics[..parsed.metrics.len()], &parsed.metrics[..]);
+        assert!(json.len() > MAX_MESSAGE_SIZE - 2 * TRUNCATION_MARKER_SIZE - 100);
+    }
+
+    #[test]
+    fn escaped_output_is_truncated() {
+        // Control characters take six bytes each once escaped.
+        let message = result_message("\u{1}".repeat(100_000), Some("e".repeat(100_000)));
+        let json = message.to_json(MAX_MESSAGE_SIZE).unwrap();
+        assert!(json.len() <= MAX_MESSAGE_SIZE);
+    }
+
+    #[test]
+    fn timestamps_to_milliseconds() {
+        let mut message = result_message(String::from("Ok check\n"), None);
+        message.executed_at = "2019-03-16T14:53:25.470766743Z".parse::<DateTime<Utc>>().unwrap();
+        message.completed_at = "2019-03-16T14:53:27.012345678Z".parse::<DateTime<Utc>>().unwrap();
+        message.duration_ms = 1541;
+        let value: serde_json::Value = serde_json::from_str(&message.to_json(MAX_MESSAGE_SIZE).unwrap()).unwrap();
+        assert_eq!("2019-01-10T11:07:44.000Z", value["scheduledAt"]);
+        assert_eq!("2019-03-16T14:53:25.470Z", value["executedAt"]);
+        assert_eq!("2019-03-16T14:53:27.012Z", value["completedAt"]);
+        assert_eq!(1541, value["durationMs"]);
+    }
+
+    #[test]
+    fn result_message_round_trip() {
+        let message = result_message(String::from("Ok check | time=1s\n"), Some(String::from("warning\n")));
+        let json = serde_json::to_string(&message).unwrap();
+        let parsed: ClientCheckResultMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(message.executed_at.timestamp_millis(), parsed.executed_at.timestamp_millis());
+        assert_eq!(message.completed_at.timestamp_millis(), parsed.completed_at.timestamp_millis());
+        assert_eq!(message.duration_ms, parsed.duration_ms);
+        assert_eq!(message.stderr, parsed.stderr);
+        assert_eq!(json, serde_json::to_string(&parsed).unwrap());
+    }
+
+    #[test]
+    fn duration_is_computed() {
+        let check = ClientCheckMessage {
+            scheduled_at: Utc::now(),
+            group: String::from("test"),
+            name: String::from("duration"),
+            command: String::from("true"),
+            timeout: 30,
+            tags: vec![],
+            capture: OutputCapture::Separate,
+            max_age: None,
+            run_as: RunAs::default(),
+        };
+        let executed_at = Utc::now() - chrono::Duration::milliseconds(1500);
+        let message = ClientCheckResultMessage::new(&check, "test-client", executed_at, CheckResultStatus::OK, String::new());
+        assert!(message.duration_ms >= 1500);
+        assert_eq!(message.completed_at.signed_duration_since(executed_at).num_milliseconds(), message.duration_ms);
+    }
+
+    #[test]
+    fn truncate_on_char_boundary() {
+        let truncated = truncate("aé", 2, 3);
+        assert_eq!("a\n[... truncated, 1 of 3 bytes shown]", truncated);
+    }
+}
@@ -1 +1,3 @@
 pub mod check;
+pub mod perfdata;
+pub mod timestamp;
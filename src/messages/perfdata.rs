@@ -0,0 +1,226 @@
+//! Nagios plugin performance data.
+//! Plugins report metrics after a `|` in their output:
+//! ```text
+//! DISK OK - free space: / 3326 MB (56%); | /=2643MB;5948;5958;0;5968
+//! / 15272 MB (77%);
+//! /boot 68 MB (69%); | /boot=68MB;88;93;0;98
+//! /home=69357MB;253404;253409;0;253414
+//! ```
+//! The performance data is the part after the `|` on the first line, plus everything
+//! after the `|` of the first long output line to contain one, including all following lines.
+//! Each metric is formatted as `'label'=value[UOM];[warn];[crit];[min];[max]`.
+
+/// A single performance data metric.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Metric {
+    pub label: String,
+    /// `None` when the plugin could not determine the value, reported as `U`.
+    pub value: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uom: Option<String>,
+    /// Warning threshold range, eg. `10`, `10:`, `~:10`, `@10:20`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warn: Option<String>,
+    /// Critical threshold range.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+}
+
+/// Parse the performance data from the plugin output.
+/// Malformed metrics are skipped.
+pub fn parse(output: &str) -> Vec<Metric> {
+    let mut perfdata = String::new();
+    let mut lines = output.lines();
+    if let Some(first) = lines.next() {
+        if let Some(index) = first.find('|') {
+            perfdata.push_str(&first[index + 1..]);
+        }
+    }
+    let mut in_perfdata = false;
+    for line in lines {
+        if in_perfdata {
+            perfdata.push(' ');
+            perfdata.push_str(line);
+        } else if let Some(index) = line.find('|') {
+            in_perfdata = true;
+            perfdata.push(' ');
+            perfdata.push_str(&line[index + 1..]);
+        }
+    }
+    tokenize(&perfdata)
+        .iter()
+        .filter_map(|(label, data)| parse_metric(label, data))
+        .collect()
+}
+
+/// Split the performance data into `(label, data)` pairs.
+/// Labels may be single-quoted to include spaces or `=`, with `''` for a literal quote.
+fn tokenize(perfdata: &str) -> Vec<(String, String)> {
+    let mut tokens = Vec::new();
+    let mut chars = perfdata.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut label = String::new();
+        if chars.peek() == Some(&'\'') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                if c == '\'' {
+                    if chars.peek() == Some(&'\'') {
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                label.push(c);
+            }
+            // Skip to the `=`.
+            while chars.peek().is_some_and(|c| *c != '=' && !c.is_whitespace()) {
+                chars.next();
+            }
+        } else {
+            while let Some(c) = chars.peek() {
+                if *c == '=' || c.is_whitespace() {
+                    break;
+                }
+                label.push(*c);
+                chars.next();
+            }
+        }
+
+        let mut data = String::new();
+        if chars.peek() == Some(&'=') {
+            chars.next();
+            while let Some(c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                data.push(*c);
+                chars.next();
+            }
+            tokens.push((label, data));
+        }
+    }
+    tokens
+}
+
+fn parse_metric(label: &str, data: &str) -> Option<Metric> {
+    if label.is_empty() {
+        return None;
+    }
+    let mut fields = data.split(';');
+    let value_uom = fields.next()?;
+    let (value, uom) = if value_uom == "U" {
+        (None, None)
+    } else {
+        let split = value_uom
+            .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+            .unwrap_or(value_uom.len());
+        let value = value_uom[..split].parse::<f64>().ok()?;
+        let uom = &value_uom[split..];
+        (Some(value), if uom.is_empty() { None } else { Some(uom.to_string()) })
+    };
+    let mut field = || fields.next().map(str::trim).filter(|f| !f.is_empty());
+    let warn = field().map(String::from);
+    let crit = field().map(String::from);
+    let min = field().and_then(|f| f.parse::<f64>().ok());
+    let max = field().and_then(|f| f.parse::<f64>().ok());
+    Some(Metric { label: label.to_string(), value, uom, warn, crit, min, max })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn metric(label: &str, value: Option<f64>, uom: Option<&str>) -> Metric {
+        Metric {
+            label: String::from(label),
+            value,
+            uom: uom.map(String::from),
+            warn: None,
+            crit: None,
+            min: None,
+            max: None,
+        }
+    }
+
+    #[test]
+    fn no_perfdata() {
+        assert!(parse("PING OK - Packet loss = 0%\n").is_empty());
+    }
+
+    #[test]
+    fn single_line() {
+        let metrics = parse("PING OK - Packet loss = 0%, RTA = 0.80 ms | rta=0.800000ms;100.000000;500.000000;0.000000 pl=0%;20;60;0\n");
+        assert_eq!(2, metrics.len());
+        assert_eq!(Metric {
+            label: String::from("rta"),
+            value: Some(0.8),
+            uom: Some(String::from("ms")),
+            warn: Some(String::from("100.000000")),
+            crit: Some(String::from("500.000000")),
+            min: Some(0.0),
+            max: None,
+        }, metrics[0]);
+        assert_eq!(Metric {
+            label: String::from("pl"),
+            value: Some(0.0),
+            uom: Some(String::from("%")),
+            warn: Some(String::from("20")),
+            crit: Some(String::from("60")),
+            min: Some(0.0),
+            max: None,
+        }, metrics[1]);
+    }
+
+    #[test]
+    fn multi_line() {
+        let output = "DISK OK - free space: / 3326 MB (56%); | /=2643MB;5948;5958;0;5968\n\
+                      / 15272 MB (77%);\n\
+                      /boot 68 MB (69%); | /boot=68MB;88;93;0;98\n\
+                      /home=69357MB;253404;253409;0;253414\n";
+        let labels: Vec<String> = parse(output).into_iter().map(|m| m.label).collect();
+        assert_eq!(vec!["/", "/boot", "/home"], labels);
+    }
+
+    #[test]
+    fn long_output_without_first_line_perfdata() {
+        let output = "OK\nline one\nline two | count=3c\n";
+        assert_eq!(vec![metric("count", Some(3.0), Some("c"))], parse(output));
+    }
+
+    #[test]
+    fn quoted_labels() {
+        let metrics = parse("OK | 'C:\\ used space'=10GB;;;0;20 'it''s'=1");
+        assert_eq!("C:\\ used space", metrics[0].label);
+        assert_eq!(Some(20.0), metrics[0].max);
+        assert_eq!(None, metrics[0].warn);
+        assert_eq!(metric("it's", Some(1.0), None), metrics[1]);
+    }
+
+    #[test]
+    fn ranges_and_unknown_values() {
+        let metrics = parse("OK | load=U;@10:20;~:30 temp=-4.5C;10:;20:");
+        assert_eq!(None, metrics[0].value);
+        assert_eq!(Some(String::from("@10:20")), metrics[0].warn);
+        assert_eq!(Some(String::from("~:30")), metrics[0].crit);
+        assert_eq!(Some(-4.5), metrics[1].value);
+        assert_eq!(Some(String::from("C")), metrics[1].uom);
+    }
+
+    #[test]
+    fn malformed_metrics_are_skipped() {
+        let metrics = parse("OK | garbage =5 time=abc size=5B");
+        assert_eq!(vec![metric("size", Some(5.0), Some("B"))], metrics);
+    }
+}
@@ -0,0 +1,50 @@
+//! RFC 3339 timestamps with millisecond precision, eg. `2019-03-16T14:53:25.470Z`.
+//! For use with `#[serde(with = "...")]`.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Deserializer, Serializer};
+use serde::de::Error;
+
+
+pub fn serialize<S: Serializer>(timestamp: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&timestamp.to_rfc3339_opts(SecondsFormat::Millis, true))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+    let timestamp = String::deserialize(deserializer)?;
+    DateTime::parse_from_rfc3339(&timestamp)
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .map_err(D::Error::custom)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Timestamped {
+        #[serde(with = "super")]
+        at: DateTime<Utc>,
+    }
+
+    #[test]
+    fn serialize_milliseconds() {
+        let at = "2019-03-16T14:53:25.470766743Z".parse::<DateTime<Utc>>().unwrap();
+        let json = serde_json::to_string(&Timestamped { at }).unwrap();
+        assert_eq!(r#"{"at":"2019-03-16T14:53:25.470Z"}"#, json);
+    }
+
+    #[test]
+    fn serialize_whole_seconds() {
+        let at = "2019-01-10T11:07:44Z".parse::<DateTime<Utc>>().unwrap();
+        let json = serde_json::to_string(&Timestamped { at }).unwrap();
+        assert_eq!(r#"{"at":"2019-01-10T11:07:44.000Z"}"#, json);
+    }
+
+    #[test]
+    fn deserialize_offset() {
+        let parsed: Timestamped = serde_json::from_str(r#"{"at":"2019-03-16T15:53:25.470+01:00"}"#).unwrap();
+        assert_eq!("2019-03-16T14:53:25.470Z".parse::<DateTime<Utc>>().unwrap(), parsed.at);
+    }
+}
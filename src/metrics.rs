@@ -0,0 +1,162 @@
+//! Process-wide counters of the AWS client usage, and of the checks not run and messages discarded or replayed.
+//! All the service clients share one HTTP client and its connection pool, so the number of
+//! requests per HTTP client shows that requests share a pool rather than each creating their own.
+//! How many connections the pool opens is not measured.
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use rusoto_core::request::DispatchSignedRequest;
+use rusoto_core::signature::SignedRequest;
+
+
+/// How often the consumer logs the metrics.
+pub const LOG_INTERVAL: Duration = Duration::from_secs(300);
+
+static HTTP_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+static SERVICE_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+static REQUESTS: AtomicUsize = AtomicUsize::new(0);
+static EXPIRED_CHECKS: AtomicUsize = AtomicUsize::new(0);
+static REJECTED_CHECKS: AtomicUsize = AtomicUsize::new(0);
+static DENIED_CHECKS: AtomicUsize = AtomicUsize::new(0);
+static DENIED_USERS: AtomicUsize = AtomicUsize::new(0);
+static DISCARDED_MESSAGES: AtomicUsize = AtomicUsize::new(0);
+static REPLAYED_MESSAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// Count a newly created HTTP client, ie. connection pool.
+pub fn http_client_created() {
+    HTTP_CLIENTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a newly created SSM, Lambda or SQS client.
+pub fn service_client_created() {
+    SERVICE_CLIENTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a check skipped for being scheduled too long ago.
+pub fn check_expired() {
+    EXPIRED_CHECKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a check rejected for not matching the client's tags.
+pub fn check_rejected() {
+    REJECTED_CHECKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a check denied by the command policy.
+pub fn check_denied() {
+    DENIED_CHECKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a check denied the user or group it would run as.
+pub fn user_denied() {
+    DENIED_USERS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a message discarded for being invalid, or failing signature verification.
+pub fn message_discarded() {
+    DISCARDED_MESSAGES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a validly signed message rejected for being received again, or scheduled outside the replay window.
+pub fn message_replayed() {
+    REPLAYED_MESSAGES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A point in time copy of the counters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Snapshot {
+    pub http_clients: usize,
+    pub service_clients: usize,
+    pub requests: usize,
+    pub expired_checks: usize,
+    pub rejected_checks: usize,
+    pub denied_checks: usize,
+    pub denied_users: usize,
+    pub discarded_messages: usize,
+    pub replayed_messages: usize,
+}
+
+pub fn snapshot() -> Snapshot {
+    Snapshot {
+        http_clients: HTTP_CLIENTS.load(Ordering::Relaxed),
+        service_clients: SERVICE_CLIENTS.load(Ordering::Relaxed),
+        requests: REQUESTS.load(Ordering::Relaxed),
+        expired_checks: EXPIRED_CHECKS.load(Ordering::Relaxed),
+        rejected_checks: REJECTED_CHECKS.load(Ordering::Relaxed),
+        denied_checks: DENIED_CHECKS.load(Ordering::Relaxed),
+        denied_users: DENIED_USERS.load(Ordering::Relaxed),
+        discarded_messages: DISCARDED_MESSAGES.load(Ordering::Relaxed),
+        replayed_messages: REPLAYED_MESSAGES.load(Ordering::Relaxed),
+    }
+}
+
+impl Snapshot {
+    /// The average number of requests sent through each HTTP client, whichever of its connections they use.
+    pub fn requests_per_http_client(&self) -> f64 {
+        if self.http_clients == 0 {
+            0.0
+        } else {
+            self.requests as f64 / self.http_clients as f64
+        }
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} AWS requests over {} HTTP client(s) ({:.1} per client), {} service client(s), \
+                   {} expired check(s), {} rejected check(s), {} check(s) denied by policy, {} check(s) denied their user, \
+                   {} discarded message(s), {} replayed message(s)",
+               self.requests, self.http_clients, self.requests_per_http_client(), self.service_clients,
+               self.expired_checks, self.rejected_checks, self.denied_checks, self.denied_users, self.discarded_messages,
+               self.replayed_messages)
+    }
+}
+
+/// Wraps the HTTP client, counting the requests dispatched.
+pub struct RequestCountingDispatcher<D> {
+    inner: D,
+}
+
+impl<D> RequestCountingDispatcher<D> {
+    pub fn new(inner: D) -> Self {
+        http_client_created();
+        Self { inner }
+    }
+}
+
+impl<D: DispatchSignedRequest> DispatchSignedRequest for RequestCountingDispatcher<D> {
+    type Future = D::Future;
+
+    fn dispatch(&self, request: SignedRequest, timeout: Option<Duration>) -> Self::Future {
+        REQUESTS.fetch_add(1, Ordering::Relaxed);
+        self.inner.dispatch(request, timeout)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn requests_per_http_client() {
+        let snapshot = Snapshot { http_clients: 2, service_clients: 3, requests: 9,
+                                  expired_checks: 1, rejected_checks: 0, denied_checks: 2, denied_users: 4,
+                                  discarded_messages: 1, replayed_messages: 3 };
+        assert_eq!(4.5, snapshot.requests_per_http_client());
+        assert_eq!("9 AWS requests over 2 HTTP client(s) (4.5 per client), 3 service client(s), \
+                    1 expired check(s), 0 rejected check(s), 2 check(s) denied by policy, 4 check(s) denied their user, \
+                    1 discarded message(s), 3 replayed message(s)",
+                   snapshot.to_string());
+    }
+
+    #[test]
+    fn no_http_clients() {
+        let snapshot = Snapshot { http_clients: 0, service_clients: 0, requests: 0,
+                                  expired_checks: 0, rejected_checks: 0, denied_checks: 0, denied_users: 0,
+                                  discarded_messages: 0, replayed_messages: 0 };
+        assert_eq!(0.0, snapshot.requests_per_http_client());
+    }
+}
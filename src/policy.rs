@@ -0,0 +1,216 @@
+//! Local allowlist of the commands checks may run.
+//! Anyone able to send to the command queue can otherwise run any shell command on the client,
+//! so with a policy the command is split into words, refusing shell syntax, and run directly
+//! rather than by the shell, only if a rule allows its executable and arguments.
+//!
+//! ```toml
+//! [[allow]]
+//! path = "/usr/lib64/nagios/plugins/*"
+//!
+//! [[allow]]
+//! path = "/usr/local/bin/check_backup"
+//! # Every argument must match one of the patterns.  Any arguments are allowed if left out.
+//! args = ["--quiet", "/srv/*"]
+//! ```
+//!
+//! Paths and arguments are [wildcard] patterns.  In the path, and argument patterns containing `/`,
+//! `*` and `?` do not match `/`, so `/srv/*` allows the files directly in `/srv` only.
+//! Arguments with `..` path components are refused, as they could escape the allowed directories.
+
+use std::error::Error;
+use std::fs;
+use std::mem;
+use std::path::{Component, Path};
+
+use crate::wildcard;
+
+
+/// The log target of the policy decisions.
+pub const AUDIT_TARGET: &str = "audit";
+/// Shell syntax refused outside quotes, as the command is not run by a shell.
+const SHELL_SYNTAX: &str = ";&|<>$`\\(){}";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    allow: Vec<Rule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    /// Pattern of the executable's absolute path.
+    path: String,
+    /// Patterns which every argument must match one of.
+    args: Option<Vec<String>>,
+}
+
+impl Rule {
+    fn allows(&self, executable: &str, args: &[String]) -> bool {
+        wildcard::matches_path(&self.path, executable) && match self.args {
+            Some(ref patterns) => args.iter().all(|arg| patterns.iter().any(|pattern| matches_arg(pattern, arg))),
+            None => true,
+        }
+    }
+}
+
+/// Whether the argument matches the pattern, as a path if the pattern contains `/`.
+fn matches_arg(pattern: &str, arg: &str) -> bool {
+    if pattern.contains('/') {
+        wildcard::matches_path(pattern, arg)
+    } else {
+        wildcard::matches(pattern, arg)
+    }
+}
+
+/// Whether the word has a `..` path component, eg. `/srv/../etc` or `--file=../x`.
+fn has_parent_dir(word: &str) -> bool {
+    word.split('/').any(|component| component == ".." || component.ends_with("=.."))
+}
+
+impl Policy {
+    /// Read the TOML policy file.
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+
+    /// The words of the command, starting with the executable, if the policy allows it.
+    /// Otherwise returns the reason it was denied.
+    pub fn evaluate(&self, command: &str) -> Result<Vec<String>, String> {
+        let words = split(command)?;
+        let (executable, args) = words.split_first().ok_or("The command is empty.")?;
+        if !executable.starts_with('/') {
+            return Err(format!("The executable `{}` is not an absolute path.", executable));
+        }
+        if Path::new(executable).components().any(|c| c == Component::ParentDir) {
+            return Err(format!("The executable path `{}` contains `..`.", executable));
+        }
+        if let Some(arg) = args.iter().find(|arg| has_parent_dir(arg)) {
+            return Err(format!("The argument `{}` contains `..`.", arg));
+        }
+        if !self.allow.iter().any(|rule| rule.allows(executable, args)) {
+            return Err(format!("No rule allows `{}` with the arguments given.", executable));
+        }
+        Ok(words)
+    }
+}
+
+/// Split the command into words the way the shell would for simple commands.
+/// Single quotes keep their contents as is, as do double quotes apart from `$`, `` ` `` and `\`
+/// which are refused.  Other shell syntax outside quotes, eg. `;`, `|` or redirections, is refused too.
+pub fn split(command: &str) -> Result<Vec<String>, String> {
+    let refused = |c: char| Err(format!("Shell syntax `{}` is not allowed.", c));
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // Whether a word has been started, which may be empty, eg. `''`.
+    let mut in_word = false;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(String::from("Unterminated single quote.")),
+                    }
+                }
+            },
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) if "$`\\".contains(c) => return refused(c),
+                        Some(c) => word.push(c),
+                        None => return Err(String::from("Unterminated double quote.")),
+                    }
+                }
+            },
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(mem::take(&mut word));
+                    in_word = false;
+                }
+            },
+            c if SHELL_SYNTAX.contains(c) => return refused(c),
+            c => {
+                in_word = true;
+                word.push(c);
+            },
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn policy() -> Policy {
+        toml::from_str(r#"
+            [[allow]]
+            path = "/usr/lib64/nagios/plugins/*"
+
+            [[allow]]
+            path = "/usr/local/bin/check_backup"
+            args = ["--quiet", "/srv/*"]
+        "#).unwrap()
+    }
+
+    #[test]
+    fn split_words() {
+        assert_eq!(vec!["/bin/check", "-w", "80%", "a b", "", "it's", "x=\"y\""],
+                   split(r#" /bin/check  -w 80% "a b" '' "it's" 'x="y"' "#).unwrap());
+        assert!(split("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn split_refuses_shell_syntax() {
+        for command in ["check; rm -rf /", "check | nc host", "check > /etc/passwd", "check $HOME",
+                        "check `id`", "check $(id)", "check \"$HOME\"", "check &", "check 'open"] {
+            assert!(split(command).is_err(), "{}", command);
+        }
+        // Quoted, it is only an argument.
+        assert_eq!(vec!["check", "a;b"], split("check 'a;b'").unwrap());
+    }
+
+    #[test]
+    fn allows_matching_rules() {
+        let policy = policy();
+        assert_eq!(vec!["/usr/lib64/nagios/plugins/check_disk", "-w", "10%"],
+                   policy.evaluate("/usr/lib64/nagios/plugins/check_disk -w 10%").unwrap());
+        assert!(policy.evaluate("/usr/local/bin/check_backup --quiet /srv/data").is_ok());
+        assert!(policy.evaluate("/usr/local/bin/check_backup /etc/shadow").is_err());
+        assert!(policy.evaluate("/bin/sh -c id").is_err());
+    }
+
+    #[test]
+    fn denies_escaping_the_allowed_paths() {
+        let policy = policy();
+        assert!(policy.evaluate("/usr/lib64/nagios/plugins/../../../../bin/sh").is_err());
+        assert!(policy.evaluate("check_disk -w 10%").is_err());
+        assert!(policy.evaluate("/usr/lib64/nagios/plugins/check_disk; id").is_err());
+        assert!(policy.evaluate("").is_err());
+        assert!(Policy::default().evaluate("/usr/lib64/nagios/plugins/check_disk").is_err());
+    }
+
+    #[test]
+    fn wildcards_stay_in_their_directory() {
+        let policy = policy();
+        assert!(policy.evaluate("/usr/lib64/nagios/plugins/contrib/check_other").is_err());
+        assert!(policy.evaluate("/usr/local/bin/check_backup /srv/../etc/shadow").is_err());
+        assert!(policy.evaluate("/usr/local/bin/check_backup /srv/data/../../etc/shadow").is_err());
+        assert!(policy.evaluate("/usr/local/bin/check_backup /srv/data/nested").is_err());
+        // Even when the rule allows any arguments.
+        assert!(policy.evaluate("/usr/lib64/nagios/plugins/check_file --path=../../etc/shadow").is_err());
+        assert!(policy.evaluate("/usr/lib64/nagios/plugins/check_file ..").is_err());
+    }
+}
@@ -0,0 +1,345 @@
+//! Running checks as an unprivileged user.
+//! The client may set the user and group checks run as, and a check may override them with its own.
+//! The check's process switches to the user's IDs, including its supplementary groups, between fork and exec,
+//! so nothing it runs keeps the client's privileges.
+//!
+//! A check may only set a user the client allows, and a group its user is a member of.
+//!
+//! Checks are refused rather than run as root, whether as a configured user or by inheriting the client's
+//! own user, unless root is explicitly allowed.
+//!
+//! The user and group lookups may block, eg. on a directory service, so the identities are cached by [Identities]
+//! rather than looked up for every check.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::ptr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use libc::{gid_t, uid_t};
+
+
+/// How long a resolved identity is used before being looked up again, picking up changes to the user's groups.
+const CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// The group ID type `getgrouplist` takes, which is a plain `int` on macOS.
+#[cfg(target_os = "macos")]
+type GroupListId = libc::c_int;
+#[cfg(not(target_os = "macos"))]
+type GroupListId = gid_t;
+
+/// The user and group to run as, as names or numeric IDs.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RunAs {
+    pub user: Option<String>,
+    /// Defaults to the user's primary group.
+    pub group: Option<String>,
+}
+
+/// The IDs a check's process switches to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+    pub user: String,
+    pub uid: uid_t,
+    pub gid: gid_t,
+    /// The supplementary groups, from the group database.
+    pub groups: Vec<gid_t>,
+}
+
+impl Identity {
+    /// Look up the user, and the group if given rather than the user's primary group.
+    /// A numeric user without a passwd entry is used as is, with no supplementary groups.
+    pub fn resolve(user: &str, group: Option<&str>) -> Result<Self, String> {
+        let (name, uid, primary_gid) = match lookup_user(user).map_err(|e| format!("Failed to look up user `{}`:  {}", user, e))? {
+            Some(entry) => entry,
+            None => match user.parse::<uid_t>() {
+                Ok(uid) => (user.to_string(), uid, uid as gid_t),
+                Err(_) => return Err(format!("Unknown user `{}`.", user)),
+            },
+        };
+        let gid = match group {
+            Some(group) => match lookup_group(group).map_err(|e| format!("Failed to look up group `{}`:  {}", group, e))? {
+                Some(gid) => gid,
+                None => group.parse::<gid_t>().map_err(|_| format!("Unknown group `{}`.", group))?,
+            },
+            None => primary_gid,
+        };
+        let groups = supplementary_groups(&name, gid).map_err(|e| format!("Failed to look up the groups of `{}`:  {}", name, e))?;
+        Ok(Self { user: name, uid, gid, groups })
+    }
+
+    /// Whether the identity is root's, or has root's group, including as a supplementary group.
+    pub fn is_root(&self) -> bool {
+        self.uid == 0 || self.gid == 0 || self.groups.contains(&0)
+    }
+
+    /// Whether the user is a member of the group, as its primary group or a supplementary one.
+    pub fn is_member(&self, gid: gid_t) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+
+    /// Run the command as the identity.
+    /// The client must run as root to switch to any other, so as root the IDs and groups are always set,
+    /// rather than the check inheriting the client's supplementary groups.
+    /// Otherwise nothing changes if the client already runs as the identity.
+    pub fn apply(&self, command: &mut Command) {
+        let current = unsafe {
+            libc::geteuid() != 0 && libc::geteuid() == self.uid && libc::getegid() == self.gid
+        };
+        if current {
+            return;
+        }
+        let identity = self.clone();
+        unsafe {
+            command.pre_exec(move || identity.switch());
+        }
+    }
+
+    /// Switch the process to the identity, dropping the supplementary groups first and the user last.
+    /// Only async-signal-safe calls are made, as this runs in the child between fork and exec.
+    fn switch(&self) -> io::Result<()> {
+        unsafe {
+            if libc::setgroups(self.groups.len() as _, self.groups.as_ptr()) != 0
+                || libc::setgid(self.gid) != 0
+                || libc::setuid(self.uid) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The identities checks run as, by the user and group they set, according to the client's configuration.
+/// Identities are resolved when first needed and cached for [CACHE_TTL], while refusals are not cached.
+pub struct Identities {
+    client: RunAs,
+    allowed_users: Vec<String>,
+    allow_root: bool,
+    resolved: Mutex<HashMap<RunAs, (Instant, Option<Identity>)>>,
+}
+
+impl Identities {
+    pub fn new(client: RunAs, allowed_users: Vec<String>, allow_root: bool) -> Self {
+        Self { client, allowed_users, allow_root, resolved: Mutex::new(HashMap::new()) }
+    }
+
+    /// The identity the check is to run as, see [for_check].
+    pub fn for_check(&self, check: &RunAs) -> Result<Option<Identity>, String> {
+        if let Some((resolved_at, identity)) = self.resolved.lock().unwrap().get(check) {
+            if resolved_at.elapsed() < CACHE_TTL {
+                return Ok(identity.clone());
+            }
+        }
+        let identity = for_check(&self.client, &self.allowed_users, check, self.allow_root)?;
+        self.resolved.lock().unwrap().insert(check.clone(), (Instant::now(), identity.clone()));
+        Ok(identity)
+    }
+}
+
+/// The identity the check is to run as, the check's user overriding the client's, or `None` to run as the client.
+/// The check's user must be the client's or one of `allowed_users`, and the check's group one its user is a member of.
+/// The group only comes from the client if the check does not set its own user.
+/// Returns the reason if the check is refused.
+pub fn for_check(client: &RunAs, allowed_users: &[String], check: &RunAs, allow_root: bool)
+                 -> Result<Option<Identity>, String>
+{
+    if let Some(ref user) = check.user {
+        let allowed = client.user.iter().chain(allowed_users)
+            .any(|allowed| same_user(allowed, user));
+        if !allowed {
+            return Err(format!("The check's user `{}` is not allowed.", user));
+        }
+    }
+    let (user, group) = match check.user {
+        Some(ref user) => (Some(user), check.group.as_ref()),
+        None => (client.user.as_ref(), check.group.as_ref().or(client.group.as_ref())),
+    };
+    let identity = match (user, group) {
+        (Some(user), group) => Identity::resolve(user, group.map(String::as_str))?,
+        (None, Some(group)) => return Err(format!("The group `{}` is set without a user.", group)),
+        (None, None) => {
+            let root = unsafe { libc::geteuid() == 0 };
+            if root && !allow_root {
+                return Err(String::from("Refusing to run checks as root, the client's user.  Set a user, or allow root."));
+            }
+            return Ok(None);
+        },
+    };
+    if let (Some(group), Some(user)) = (check.group.as_ref(), user) {
+        if !Identity::resolve(user, None)?.is_member(identity.gid) {
+            return Err(format!("The check's user `{}` is not a member of its group `{}`.", identity.user, group));
+        }
+    }
+    if identity.is_root() && !allow_root {
+        return Err(format!("Refusing to run checks as root, user `{}` ({}) group {}.", identity.user, identity.uid, identity.gid));
+    }
+    Ok(Some(identity))
+}
+
+/// Whether the users, given as names or numeric IDs, are the same.
+fn same_user(a: &str, b: &str) -> bool {
+    if a == b {
+        return true;
+    }
+    let uid = |user: &str| match lookup_user(user) {
+        Ok(Some((_, uid, _))) => Some(uid),
+        _ => user.parse::<uid_t>().ok(),
+    };
+    matches!((uid(a), uid(b)), (Some(a), Some(b)) if a == b)
+}
+
+/// The name, user ID and primary group ID of the user, looked up by name or numeric ID.
+fn lookup_user(user: &str) -> io::Result<Option<(String, uid_t, gid_t)>> {
+    let name = CString::new(user)?;
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        let mut entry: libc::passwd = unsafe { mem::zeroed() };
+        let mut result = ptr::null_mut();
+        let ret = unsafe {
+            match user.parse::<uid_t>() {
+                Ok(uid) => libc::getpwuid_r(uid, &mut entry, buf.as_mut_ptr(), buf.len(), &mut result),
+                Err(_) => libc::getpwnam_r(name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut result),
+            }
+        };
+        match ret {
+            0 if result.is_null() => return Ok(None),
+            0 => {
+                let name = unsafe { CStr::from_ptr(entry.pw_name) }.to_string_lossy().into_owned();
+                return Ok(Some((name, entry.pw_uid, entry.pw_gid)));
+            },
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            _ => return Err(io::Error::from_raw_os_error(ret)),
+        }
+    }
+}
+
+/// The ID of the group, looked up by name or numeric ID.
+fn lookup_group(group: &str) -> io::Result<Option<gid_t>> {
+    let name = CString::new(group)?;
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        let mut entry: libc::group = unsafe { mem::zeroed() };
+        let mut result = ptr::null_mut();
+        let ret = unsafe {
+            match group.parse::<gid_t>() {
+                Ok(gid) => libc::getgrgid_r(gid, &mut entry, buf.as_mut_ptr(), buf.len(), &mut result),
+                Err(_) => libc::getgrnam_r(name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut result),
+            }
+        };
+        match ret {
+            0 if result.is_null() => return Ok(None),
+            0 => return Ok(Some(entry.gr_gid)),
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            _ => return Err(io::Error::from_raw_os_error(ret)),
+        }
+    }
+}
+
+/// The groups the user is a member of, including `gid`.
+fn supplementary_groups(user: &str, gid: gid_t) -> io::Result<Vec<gid_t>> {
+    let name = CString::new(user)?;
+    let mut groups: Vec<GroupListId> = vec![0; 32];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let ret = unsafe { libc::getgrouplist(name.as_ptr(), gid as GroupListId, groups.as_mut_ptr(), &mut count) };
+        if ret >= 0 {
+            groups.truncate(count as usize);
+            return Ok(groups.into_iter().map(|gid| gid as gid_t).collect());
+        }
+        // `count` is set to the number of groups needed.
+        let needed = (count as usize).max(groups.len() * 2);
+        groups.resize(needed, 0);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run_as(user: Option<&str>, group: Option<&str>) -> RunAs {
+        RunAs { user: user.map(String::from), group: group.map(String::from) }
+    }
+
+    #[test]
+    fn resolves_users_and_groups() {
+        let root = Identity::resolve("root", None).unwrap();
+        assert_eq!((0, 0), (root.uid, root.gid));
+        assert!(root.groups.contains(&0));
+        assert_eq!(root, Identity::resolve("0", None).unwrap());
+        // Numeric IDs need not exist.
+        let numeric = Identity::resolve("54321", Some("54322")).unwrap();
+        assert_eq!((54321, 54322), (numeric.uid, numeric.gid));
+        assert!(Identity::resolve("no-such-user-smdf", None).is_err());
+        assert!(Identity::resolve("root", Some("no-such-group-smdf")).is_err());
+    }
+
+    #[test]
+    fn checks_override_the_client() {
+        let client = run_as(Some("54321"), Some("54322"));
+        let allowed = vec![String::from("54323")];
+        let identity = for_check(&client, &allowed, &RunAs::default(), false).unwrap().unwrap();
+        assert_eq!((54321, 54322), (identity.uid, identity.gid));
+        // The check's user comes with its own primary group, not the client's.
+        let identity = for_check(&client, &allowed, &run_as(Some("54323"), None), false).unwrap().unwrap();
+        assert_eq!((54323, 54323), (identity.uid, identity.gid));
+        let identity = for_check(&client, &allowed, &run_as(None, Some("54321")), false).unwrap().unwrap();
+        assert_eq!((54321, 54321), (identity.uid, identity.gid));
+        assert!(for_check(&RunAs::default(), &allowed, &run_as(None, Some("54324")), false).is_err());
+    }
+
+    #[test]
+    fn checks_only_set_allowed_users_and_their_groups() {
+        let client = run_as(Some("54321"), None);
+        let allowed = vec![String::from("54323")];
+        assert!(for_check(&client, &allowed, &run_as(Some("54324"), None), false).is_err());
+        assert!(for_check(&client, &[], &run_as(Some("54323"), None), false).is_err());
+        // The client's own user is always allowed.
+        assert!(for_check(&client, &[], &run_as(Some("54321"), None), false).is_ok());
+        // Numeric IDs without a group entry are only members of their primary group.
+        assert!(for_check(&client, &allowed, &run_as(None, Some("54324")), false).is_err());
+        assert!(for_check(&client, &allowed, &run_as(Some("54323"), Some("54324")), false).is_err());
+        assert!(for_check(&client, &allowed, &run_as(Some("54323"), Some("54323")), false).is_ok());
+        // Whereas the client's group is trusted.
+        assert!(for_check(&run_as(Some("54321"), Some("54324")), &[], &RunAs::default(), false).is_ok());
+    }
+
+    #[test]
+    fn refuses_root_unless_allowed() {
+        let root = run_as(Some("root"), None);
+        let allowed = vec![String::from("root")];
+        assert!(for_check(&root, &[], &RunAs::default(), false).is_err());
+        assert!(for_check(&RunAs::default(), &allowed, &root, false).is_err());
+        assert!(for_check(&run_as(Some("54321"), Some("0")), &[], &RunAs::default(), false).is_err());
+        assert!(for_check(&root, &[], &RunAs::default(), true).unwrap().is_some());
+        let client_is_root = unsafe { libc::geteuid() == 0 };
+        assert_eq!(client_is_root, for_check(&RunAs::default(), &[], &RunAs::default(), false).is_err());
+        assert_eq!(None, for_check(&RunAs::default(), &[], &RunAs::default(), true).unwrap());
+    }
+
+    #[test]
+    fn identities_are_cached() {
+        let identities = Identities::new(run_as(Some("54321"), None), vec![], false);
+        let identity = identities.for_check(&RunAs::default()).unwrap();
+        assert_eq!(Some(54321), identity.as_ref().map(|identity| identity.uid));
+        assert_eq!(identity, identities.for_check(&RunAs::default()).unwrap());
+        assert_eq!(1, identities.resolved.lock().unwrap().len());
+        // Refusals are not cached.
+        assert!(identities.for_check(&run_as(Some("54323"), None)).is_err());
+        assert_eq!(1, identities.resolved.lock().unwrap().len());
+    }
+
+    #[test]
+    fn root_supplementary_group_is_root() {
+        let identity = Identity { user: String::from("wheel"), uid: 54321, gid: 54321, groups: vec![54321, 0] };
+        assert!(identity.is_root());
+        assert!(!Identity { groups: vec![54321], ..identity }.is_root());
+    }
+}
@@ -0,0 +1,324 @@
+//! Verification of signed command messages.
+//! A signed message is an envelope carrying the check message as its payload, along with the ID of
+//! the key it was signed with and the base64 encoded signature of the payload's bytes:
+//!
+//! ```json
+//! {"payload": "{\"scheduledAt\":\"2019-01-10T11:07:44Z\",...}", "keyId": "backend-1", "signature": "..."}
+//! ```
+//!
+//! Payloads are signed with Ed25519, or HMAC-SHA256 with a shared secret.  The keys are read from a
+//! local TOML file, and once configured every message must be signed by one of them:
+//!
+//! ```toml
+//! [[key]]
+//! id = "backend-1"
+//! algorithm = "ed25519"
+//! public-key = "<base64 encoded 32 byte public key>"
+//!
+//! [[key]]
+//! id = "backend-legacy"
+//! algorithm = "hmac-sha256"
+//! secret = "<base64 encoded secret>"
+//! ```
+//!
+//! A message is only accepted while its `scheduledAt` time is within the replay window, and only once:
+//! its signature is remembered for as long as the message could be accepted, and rejected if seen again.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use ring::hmac;
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+use crate::messages::check::ClientCheckMessage;
+
+
+/// The shortest HMAC secret accepted, in bytes.
+const MIN_SECRET_SIZE: usize = 16;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeysFile {
+    #[serde(rename = "key", default)]
+    keys: Vec<KeyEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct KeyEntry {
+    id: String,
+    algorithm: Algorithm,
+    public_key: Option<String>,
+    secret: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Algorithm {
+    Ed25519,
+    HmacSha256,
+}
+
+enum Key {
+    Ed25519(Vec<u8>),
+    HmacSha256(hmac::Key),
+}
+
+/// A signed command message.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Envelope {
+    pub payload: String,
+    #[serde(rename = "keyId")]
+    pub key_id: String,
+    pub signature: String,
+}
+
+/// The key ID and signature of a message.
+type Signed = (String, Vec<u8>);
+
+/// Why a message was rejected.
+#[derive(Debug)]
+pub enum Rejected {
+    /// Unsigned, invalid or not signed by a known key.
+    Invalid(String),
+    /// Validly signed, but already received.
+    Replayed,
+    /// Validly signed, so can be trusted to say where a result would go, but scheduled outside the replay window.
+    OutsideWindow(Box<ClientCheckMessage>, Duration),
+}
+
+impl From<String> for Rejected {
+    fn from(reason: String) -> Self {
+        Rejected::Invalid(reason)
+    }
+}
+
+impl From<&str> for Rejected {
+    fn from(reason: &str) -> Self {
+        Rejected::Invalid(reason.to_string())
+    }
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejected::Invalid(reason) => f.write_str(reason),
+            Rejected::Replayed => f.write_str("The message has already been received, so is a replay."),
+            Rejected::OutsideWindow(check, window) =>
+                write!(f, "Scheduled at {}, outside the replay window of {} seconds.", check.scheduled_at, window.as_secs()),
+        }
+    }
+}
+
+pub struct Verifier {
+    keys: HashMap<String, Key>,
+    /// How far `scheduledAt` may be from now, either way.
+    window: Duration,
+    /// The key IDs and signatures of the messages accepted, until they fall outside the replay window.
+    seen: Mutex<HashMap<Signed, DateTime<Utc>>>,
+}
+
+impl Verifier {
+    /// Read the TOML keys file.
+    pub fn from_file(path: &Path, window: Duration) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        let file: KeysFile = toml::from_str(&contents)?;
+        let mut keys = HashMap::new();
+        for entry in file.keys {
+            let key = match (entry.algorithm, entry.public_key, entry.secret) {
+                (Algorithm::Ed25519, Some(public_key), None) => {
+                    let public_key = base64::decode(&public_key)?;
+                    if public_key.len() != 32 {
+                        return Err(format!("Ed25519 key `{}` is not 32 bytes.", entry.id).into());
+                    }
+                    Key::Ed25519(public_key)
+                },
+                (Algorithm::HmacSha256, None, Some(secret)) => {
+                    let secret = base64::decode(&secret)?;
+                    if secret.len() < MIN_SECRET_SIZE {
+                        return Err(format!("HMAC key `{}` is shorter than {} bytes.", entry.id, MIN_SECRET_SIZE).into());
+                    }
+                    Key::HmacSha256(hmac::Key::new(hmac::HMAC_SHA256, &secret))
+                },
+                _ => return Err(format!("Key `{}` needs a `public-key` for ed25519, or a `secret` for hmac-sha256.", entry.id).into()),
+            };
+            if keys.insert(entry.id.clone(), key).is_some() {
+                return Err(format!("Key `{}` is defined more than once.", entry.id).into());
+            }
+        }
+        if keys.is_empty() {
+            return Err("No keys defined.".into());
+        }
+        Ok(Self { keys, window, seen: Mutex::new(HashMap::new()) })
+    }
+
+    /// The check message of the signed body, if its signature is valid, it was scheduled within the replay window
+    /// and it has not been seen before.  Otherwise returns the reason it was rejected.
+    pub fn verify(&self, body: &str, now: DateTime<Utc>) -> Result<ClientCheckMessage, Rejected> {
+        let envelope = envelope(body)?.ok_or("The message is not signed.")?;
+        let key = self.keys.get(&envelope.key_id)
+            .ok_or_else(|| format!("Unknown signing key `{}`.", envelope.key_id))?;
+        let signature = base64::decode(&envelope.signature)
+            .map_err(|e| format!("Invalid signature encoding:  {}", e))?;
+        let valid = match key {
+            Key::Ed25519(public_key) => UnparsedPublicKey::new(&ED25519, public_key)
+                .verify(envelope.payload.as_bytes(), &signature).is_ok(),
+            Key::HmacSha256(key) => hmac::verify(key, envelope.payload.as_bytes(), &signature).is_ok(),
+        };
+        if !valid {
+            return Err(Rejected::Invalid(format!("Invalid signature by key `{}`.", envelope.key_id)));
+        }
+        let check: ClientCheckMessage = serde_json::from_str(&envelope.payload)
+            .map_err(|e| format!("Invalid payload:  {}", e))?;
+        let mut seen = self.seen.lock().unwrap();
+        // Once past the end of their window, messages would be rejected anyway so can be forgotten.
+        seen.retain(|_, until| *until >= now);
+        let skew = now.signed_duration_since(check.scheduled_at).num_seconds().unsigned_abs();
+        if skew > self.window.as_secs() {
+            return Err(Rejected::OutsideWindow(Box::new(check), self.window));
+        }
+        let until = check.scheduled_at + chrono::Duration::seconds(self.window.as_secs() as i64);
+        if seen.insert((envelope.key_id, signature), until).is_some() {
+            return Err(Rejected::Replayed);
+        }
+        Ok(check)
+    }
+}
+
+/// The check message of the body, unwrapping a signed message without verifying it.
+pub fn unverified(body: &str) -> Result<ClientCheckMessage, String> {
+    let payload = match envelope(body)? {
+        Some(envelope) => envelope.payload,
+        None => body.to_string(),
+    };
+    serde_json::from_str(&payload).map_err(|e| format!("Invalid message:  {}", e))
+}
+
+/// The envelope, if the body is a signed message.
+fn envelope(body: &str) -> Result<Option<Envelope>, String> {
+    let value: serde_json::Value = serde_json::from_str(body).map_err(|e| format!("Invalid message:  {}", e))?;
+    if value.get("signature").is_none() {
+        return Ok(None);
+    }
+    serde_json::from_value(value)
+        .map(Some)
+        .map_err(|e| format!("Invalid signed message:  {}", e))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    use chrono::Duration as OldDuration;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn payload(scheduled_at: DateTime<Utc>) -> String {
+        format!("{{\"scheduledAt\":\"{}\",\"group\":\"test\",\"name\":\"signed\",\"command\":\"true\",\"timeout\":30,\"tags\":[]}}",
+                scheduled_at.format("%Y-%m-%dT%H:%M:%SZ"))
+    }
+
+    fn sign(key_id: &str, payload: &str, signature: &[u8]) -> String {
+        serde_json::to_string(&Envelope {
+            payload: payload.to_string(),
+            key_id: key_id.to_string(),
+            signature: base64::encode(signature),
+        }).unwrap()
+    }
+
+    /// A verifier with the keys file, along with the Ed25519 key pair.
+    fn verifier(name: &str) -> (Verifier, Ed25519KeyPair) {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let path = env::temp_dir().join(format!("smdf-keys-{}-{}.toml", process::id(), name));
+        fs::write(&path, format!(r#"
+            [[key]]
+            id = "ed"
+            algorithm = "ed25519"
+            public-key = "{}"
+
+            [[key]]
+            id = "hmac"
+            algorithm = "hmac-sha256"
+            secret = "{}"
+        "#, base64::encode(key_pair.public_key().as_ref()), base64::encode(SECRET))).unwrap();
+        let verifier = Verifier::from_file(&path, Duration::from_secs(300)).unwrap();
+        fs::remove_file(&path).unwrap();
+        (verifier, key_pair)
+    }
+
+    #[test]
+    fn valid_signatures() {
+        let (verifier, key_pair) = verifier("valid");
+        let now = Utc::now();
+        let payload = payload(now);
+        let check = verifier.verify(&sign("ed", &payload, key_pair.sign(payload.as_bytes()).as_ref()), now).unwrap();
+        assert_eq!("signed", check.name);
+        let hmac_key = hmac::Key::new(hmac::HMAC_SHA256, SECRET);
+        let signature = hmac::sign(&hmac_key, payload.as_bytes());
+        assert!(verifier.verify(&sign("hmac", &payload, signature.as_ref()), now).is_ok());
+    }
+
+    #[test]
+    fn rejects_unsigned_and_tampered_messages() {
+        let (verifier, key_pair) = verifier("tampered");
+        let now = Utc::now();
+        let payload = payload(now);
+        let signature = key_pair.sign(payload.as_bytes());
+
+        assert_eq!("The message is not signed.", verifier.verify(&payload, now).unwrap_err().to_string());
+        let tampered = payload.replace("true", "id");
+        assert!(verifier.verify(&sign("ed", &tampered, signature.as_ref()), now).is_err());
+        // Signed with a different key than claimed.
+        assert!(verifier.verify(&sign("hmac", &payload, signature.as_ref()), now).is_err());
+        assert!(verifier.verify(&sign("other", &payload, signature.as_ref()), now).is_err());
+    }
+
+    #[test]
+    fn enforces_replay_window() {
+        let (verifier, key_pair) = verifier("window");
+        let now = Utc::now();
+        for scheduled_at in [now - OldDuration::seconds(600), now + OldDuration::seconds(600)] {
+            let payload = payload(scheduled_at);
+            let message = sign("ed", &payload, key_pair.sign(payload.as_bytes()).as_ref());
+            match verifier.verify(&message, now) {
+                Err(Rejected::OutsideWindow(check, _)) => assert_eq!("signed", check.name),
+                other => panic!("Accepted outside the replay window:  {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_replays_within_window() {
+        let (verifier, key_pair) = verifier("replay");
+        let now = Utc::now();
+        let payload = payload(now);
+        let message = sign("ed", &payload, key_pair.sign(payload.as_bytes()).as_ref());
+        assert!(verifier.verify(&message, now).is_ok());
+        assert!(matches!(verifier.verify(&message, now + OldDuration::seconds(60)), Err(Rejected::Replayed)));
+        // Another message by the same key is still accepted.
+        let other = payload.replace("true", "false");
+        assert!(verifier.verify(&sign("ed", &other, key_pair.sign(other.as_bytes()).as_ref()), now).is_ok());
+        // Seen signatures are forgotten once outside the window.
+        verifier.verify(&message, now + OldDuration::seconds(600)).unwrap_err();
+        assert!(verifier.seen.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unverified_unwraps_envelope() {
+        let payload = payload(Utc::now());
+        assert_eq!("signed", unverified(&payload).unwrap().name);
+        assert_eq!("signed", unverified(&sign("ed", &payload, b"invalid")).unwrap().name);
+    }
+}
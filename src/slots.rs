@@ -0,0 +1,173 @@
+//! Limit on the number of checks running concurrently.
+//! Checks run as tasks on the async runtime rather than on dedicated threads,
+//! so a slot is held for each running check and released when it completes.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use futures::{Async, Future, Poll};
+use futures::task::{self, Task};
+
+
+#[derive(Default)]
+struct State {
+    busy: usize,
+    /// The ID of the next waiter.
+    next_waiter: usize,
+    /// Tasks waiting for slots to be released, by waiter ID, so polling again replaces the waiter's task.
+    waiting: HashMap<usize, Task>,
+}
+
+#[derive(Clone)]
+pub struct Slots {
+    size: usize,
+    state: Arc<Mutex<State>>,
+}
+
+impl Slots {
+    pub fn new(size: usize) -> Self {
+        assert!(size > 0, "The number of slots must be greater than zero.");
+        Self { size, state: Arc::new(Mutex::new(State::default())) }
+    }
+
+    /// The total number of slots.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The number of slots not currently held.
+    pub fn idle(&self) -> usize {
+        self.size.saturating_sub(self.state.lock().unwrap().busy)
+    }
+
+    /// Take a slot, which is released when the returned [Slot] is dropped.
+    /// Slots may be over-committed, so callers should wait for an idle slot first.
+    pub fn acquire(&self) -> Slot {
+        self.state.lock().unwrap().busy += 1;
+        Slot { state: self.state.clone() }
+    }
+
+    /// Resolves with the number of idle slots once at least one is idle.
+    pub fn wait_for_idle(&self) -> WaitForIdle {
+        self.waiter(false)
+    }
+
+    /// Resolves once every slot is idle.
+    pub fn wait_for_all_idle(&self) -> WaitForIdle {
+        self.waiter(true)
+    }
+
+    fn waiter(&self, all: bool) -> WaitForIdle {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_waiter;
+        state.next_waiter = state.next_waiter.wrapping_add(1);
+        WaitForIdle { slots: self.clone(), all, id }
+    }
+}
+
+/// A slot held by a running check.
+pub struct Slot {
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.busy -= 1;
+        for (_, task) in state.waiting.drain() {
+            task.notify();
+        }
+    }
+}
+
+pub struct WaitForIdle {
+    slots: Slots,
+    all: bool,
+    id: usize,
+}
+
+impl Future for WaitForIdle {
+    type Item = usize;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<usize, ()> {
+        let mut state = self.slots.state.lock().unwrap();
+        let idle = self.slots.size.saturating_sub(state.busy);
+        if (self.all && state.busy == 0) || (!self.all && idle > 0) {
+            state.waiting.remove(&self.id);
+            Ok(Async::Ready(idle))
+        } else {
+            state.waiting.insert(self.id, task::current());
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl Drop for WaitForIdle {
+    fn drop(&mut self) {
+        self.slots.state.lock().unwrap().waiting.remove(&self.id);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn idle_count_tracks_held_slots() {
+        let slots = Slots::new(2);
+        let first = slots.acquire();
+        assert_eq!(1, slots.idle());
+        assert_eq!(Ok(1), slots.wait_for_idle().wait());
+        let second = slots.acquire();
+        assert_eq!(0, slots.idle());
+        drop(first);
+        drop(second);
+        assert_eq!(2, slots.idle());
+    }
+
+    #[test]
+    fn waits_for_a_slot_to_be_released() {
+        let slots = Slots::new(1);
+        let slot = slots.acquire();
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(slot);
+        });
+        assert_eq!(Ok(1), slots.wait_for_idle().wait());
+        releaser.join().unwrap();
+    }
+
+    #[test]
+    fn polling_again_replaces_the_waiting_task() {
+        let slots = Slots::new(1);
+        let _slot = slots.acquire();
+        let mut waiter = slots.wait_for_idle();
+        futures::future::lazy(|| {
+            for _ in 0..10 {
+                assert_eq!(Ok(Async::NotReady), waiter.poll());
+            }
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+        assert_eq!(1, slots.state.lock().unwrap().waiting.len());
+        drop(waiter);
+        assert!(slots.state.lock().unwrap().waiting.is_empty());
+    }
+
+    #[test]
+    fn waits_for_all_slots_to_be_released() {
+        let slots = Slots::new(4);
+        let held: Vec<Slot> = (0..3).map(|_| slots.acquire()).collect();
+        let releaser = thread::spawn(move || {
+            for slot in held {
+                thread::sleep(Duration::from_millis(20));
+                drop(slot);
+            }
+        });
+        assert_eq!(Ok(4), slots.wait_for_all_idle().wait());
+        releaser.join().unwrap();
+    }
+}
@@ -0,0 +1,422 @@
+//! On-disk spool of the check results which could not be sent to the result queue.
+//! Each result is written to its own file, named by a sequence number so that results are
+//! replayed in the order they were spooled.  A check's command message is only deleted once its
+//! result has been sent or spooled, so results are not lost while the result queue is unreachable.
+//! Results the result queue rejects are kept aside, counting towards the spool's size, until they
+//! reach the maximum age.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use log::{error, info, warn};
+
+use crate::batch::MAX_BATCH_SIZE;
+use crate::deadline::Worker;
+use crate::messages::check::{ClientCheckResultMessage, MAX_MESSAGE_SIZE};
+use crate::transport::ResultSink;
+
+
+/// How often the spooled results are replayed.
+pub const REPLAY_INTERVAL: Duration = Duration::from_secs(30);
+const EXTENSION: &str = "json";
+/// Results are written with this extension, then renamed.  Left behind only by a crash mid-write.
+const TMP_EXTENSION: &str = "tmp";
+/// Rejected results are kept aside with this extension, and never replayed.
+const REJECTED_EXTENSION: &str = "rejected";
+
+pub struct Spool {
+    dir: PathBuf,
+    max_size: u64,
+    max_age: Duration,
+    /// The sequence number of the next result, serializing the writes.
+    next_seq: Mutex<u64>,
+}
+
+impl Spool {
+    /// Open the spool directory, creating it if needed, and remove any partially written results.
+    /// At most `max_size` bytes of results are kept, and results older than `max_age` are discarded.
+    pub fn open(dir: &Path, max_size: u64, max_age: Duration) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let spool = Self { dir: dir.to_path_buf(), max_size, max_age, next_seq: Mutex::new(0) };
+        for path in spool.files(TMP_EXTENSION)? {
+            warn!("Removing partially written result {}.", path.display());
+            remove(&path);
+        }
+        spool.prune_rejected()?;
+        // Continuing after the rejected results too, so they are never overwritten.
+        let next_seq = spool.files(EXTENSION)?.into_iter()
+            .chain(spool.files(REJECTED_EXTENSION)?)
+            .filter_map(|path| seq(&path))
+            .max()
+            .map_or(0, |seq| seq + 1);
+        *spool.next_seq.lock().unwrap() = next_seq;
+        Ok(spool)
+    }
+
+    /// Durably write the result to the spool.
+    /// Fails if the spool would exceed its maximum size.
+    pub fn write(&self, result: &ClientCheckResultMessage) -> io::Result<()> {
+        let body = result.to_json(MAX_MESSAGE_SIZE)?;
+        let mut next_seq = self.next_seq.lock().unwrap();
+        let size = self.size()?;
+        if size + body.len() as u64 > self.max_size {
+            return Err(io::Error::other(format!("Spool is full, {} of {} bytes used.", size, self.max_size)));
+        }
+        let path = self.dir.join(format!("{:020}.{}", *next_seq, EXTENSION));
+        // Written aside and renamed into place, so a crash never leaves a partial result.
+        let tmp_path = path.with_extension(TMP_EXTENSION);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(body.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        File::open(&self.dir)?.sync_all()?;
+        *next_seq += 1;
+        Ok(())
+    }
+
+    /// The number of spooled results.
+    pub fn len(&self) -> usize {
+        self.entries().map(|entries| entries.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Send the spooled results to the sink, oldest first, stopping at the first failure.
+    /// Results older than the maximum age, or which cannot be read, are discarded.
+    /// Results the sink rejects are renamed aside, see [REJECTED_EXTENSION].
+    /// Returns the number of results sent.
+    pub fn replay(&self, sink: &dyn ResultSink) -> usize {
+        if let Err(e) = self.prune_rejected() {
+            error!("Failed to remove expired rejected results from {}:  {}", self.dir.display(), e);
+        }
+        let entries = match self.entries() {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Failed to read spool directory {}:  {}", self.dir.display(), e);
+                return 0;
+            },
+        };
+        let mut sent = 0;
+        for batch in entries.chunks(MAX_BATCH_SIZE) {
+            let mut results = Vec::new();
+            let mut paths = Vec::new();
+            for (_, path) in batch {
+                match self.read(path) {
+                    Ok(Some(result)) => {
+                        results.push(result);
+                        paths.push(path);
+                    },
+                    Ok(None) => remove(path),
+                    Err(e) => {
+                        error!("Discarding unreadable spooled result {}:  {}", path.display(), e);
+                        remove(path);
+                    },
+                }
+            }
+            if results.is_empty() {
+                continue;
+            }
+            let failed = sink.send(&results);
+            for (index, path) in paths.into_iter().enumerate() {
+                if let Some(reason) = failed.rejected(index) {
+                    let rejected_path = path.with_extension(REJECTED_EXTENSION);
+                    error!("Spooled result {} was rejected, keeping it as {}:  {}",
+                           path.display(), rejected_path.display(), reason);
+                    if let Err(e) = fs::rename(path, &rejected_path) {
+                        error!("Failed to rename rejected result {}:  {}", path.display(), e);
+                    }
+                } else if failed.sent(index) {
+                    remove(path);
+                    sent += 1;
+                }
+            }
+            if !failed.retryable.is_empty() {
+                break;
+            }
+        }
+        sent
+    }
+
+    /// Read a spooled result, or `None` if it has expired.
+    fn read(&self, path: &Path) -> Result<Option<ClientCheckResultMessage>, Box<dyn std::error::Error>> {
+        let result: ClientCheckResultMessage = serde_json::from_str(&fs::read_to_string(path)?)?;
+        let age = Utc::now().signed_duration_since(result.completed_at).to_std().unwrap_or_default();
+        if age > self.max_age {
+            warn!("Discarding spooled result of {}/{} completed {} seconds ago.", result.group, result.name, age.as_secs());
+            return Ok(None);
+        }
+        Ok(Some(result))
+    }
+
+    /// Remove the rejected results kept aside for longer than the maximum age.
+    fn prune_rejected(&self) -> io::Result<()> {
+        for path in self.files(REJECTED_EXTENSION)? {
+            let age = fs::metadata(&path)?.modified()?.elapsed().unwrap_or_default();
+            if age > self.max_age {
+                warn!("Removing rejected result {} kept for {} seconds.", path.display(), age.as_secs());
+                remove(&path);
+            }
+        }
+        Ok(())
+    }
+
+    /// The spooled result files in sequence order.
+    fn entries(&self) -> io::Result<Vec<(u64, PathBuf)>> {
+        let mut entries: Vec<(u64, PathBuf)> = self.files(EXTENSION)?
+            .into_iter()
+            .filter_map(|path| seq(&path).map(|seq| (seq, path)))
+            .collect();
+        entries.sort();
+        Ok(entries)
+    }
+
+    /// The files in the spool directory with the extension.
+    fn files(&self, extension: &str) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some(extension) {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    /// The total size of the spooled and rejected results, in bytes.
+    fn size(&self) -> io::Result<u64> {
+        let mut size = 0;
+        for path in self.files(EXTENSION)?.into_iter().chain(self.files(REJECTED_EXTENSION)?) {
+            size += fs::metadata(path)?.len();
+        }
+        Ok(size)
+    }
+}
+
+/// The sequence number of the result file.
+fn seq(path: &Path) -> Option<u64> {
+    path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok())
+}
+
+fn remove(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        error!("Failed to remove spooled result {}:  {}", path.display(), e);
+    }
+}
+
+/// Replays the spool on a background thread every [REPLAY_INTERVAL].
+pub struct Replay {
+    /// Sent the deadline of the final attempt when stopping.
+    stop: Mutex<Option<mpsc::Sender<Instant>>>,
+    worker: Mutex<Option<Worker>>,
+}
+
+impl Replay {
+    /// Start replaying, beginning with any results left from a previous run.
+    pub fn start(spool: Arc<Spool>, sink: Arc<dyn ResultSink>) -> Self {
+        let (stop, stopped) = mpsc::channel::<Instant>();
+        let worker = Worker::spawn("spool-replay", move || {
+            replay(&spool, sink.as_ref());
+            loop {
+                match stopped.recv_timeout(REPLAY_INTERVAL) {
+                    Err(RecvTimeoutError::Timeout) => replay(&spool, sink.as_ref()),
+                    // Stopped, make a final attempt if there is time.
+                    Ok(deadline) => {
+                        if Instant::now() < deadline {
+                            replay(&spool, sink.as_ref());
+                        }
+                        break;
+                    },
+                    Err(RecvTimeoutError::Disconnected) => {
+                        replay(&spool, sink.as_ref());
+                        break;
+                    },
+                }
+            }
+        }).expect("Failed to spawn spool replay thread.");
+        Self {
+            stop: Mutex::new(Some(stop)),
+            worker: Mutex::new(Some(worker)),
+        }
+    }
+
+    /// Stop replaying, after a final attempt to send the spooled results if it starts before the deadline.
+    /// Waits until the deadline at most, returning whether the replay finished.
+    /// Results not replayed stay spooled for the next run.
+    pub fn stop_by(&self, deadline: Instant) -> bool {
+        if let Some(stop) = self.stop.lock().unwrap().take() {
+            let _ = stop.send(deadline);
+        }
+        match self.worker.lock().unwrap().take() {
+            Some(worker) => worker.join_by(deadline),
+            None => true,
+        }
+    }
+}
+
+fn replay(spool: &Spool, sink: &dyn ResultSink) {
+    if spool.is_empty() {
+        return;
+    }
+    let sent = spool.replay(sink);
+    let remaining = spool.len();
+    if sent > 0 {
+        info!("Replayed {} spooled result(s), {} remaining.", sent, remaining);
+    } else if remaining > 0 {
+        warn!("Failed to replay spooled results, {} remaining.", remaining);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::time::SystemTime;
+
+    use chrono::Duration as OldDuration;
+
+    use crate::messages::check::{ClientCheckMessage, CheckResultStatus};
+    use crate::transport::memory::MemoryTransport;
+
+    /// A fresh spool directory for the test.
+    fn spool_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("smdf-spool-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn result(name: &str) -> ClientCheckResultMessage {
+        let check: ClientCheckMessage = serde_json::from_str(&format!(
+            "{{\"scheduledAt\":\"2019-01-10T11:07:44Z\",\"group\":\"test\",\"name\":\"{}\",\"command\":\"true\",\"timeout\":30,\"tags\":[]}}",
+            name)).unwrap();
+        ClientCheckResultMessage::new(&check, "test-client", Utc::now(), CheckResultStatus::OK, String::from("ok"))
+    }
+
+    #[test]
+    fn replays_in_order() {
+        let dir = spool_dir("order");
+        let spool = Spool::open(&dir, 1_000_000, Duration::from_secs(3600)).unwrap();
+        for i in 0..15 {
+            spool.write(&result(&format!("check-{}", i))).unwrap();
+        }
+        assert_eq!(15, spool.len());
+
+        // Sequence numbers continue after re-opening.
+        let spool = Spool::open(&dir, 1_000_000, Duration::from_secs(3600)).unwrap();
+        spool.write(&result("check-15")).unwrap();
+
+        let transport = MemoryTransport::new();
+        assert_eq!(16, spool.replay(&transport));
+        let names: Vec<String> = transport.results().into_iter().map(|r| r.name).collect();
+        assert_eq!((0..16).map(|i| format!("check-{}", i)).collect::<Vec<String>>(), names);
+        assert!(spool.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_replay_keeps_results() {
+        let dir = spool_dir("failed");
+        let spool = Spool::open(&dir, 1_000_000, Duration::from_secs(3600)).unwrap();
+        spool.write(&result("check")).unwrap();
+
+        let transport = MemoryTransport::new();
+        transport.fail_sends(1);
+        assert_eq!(0, spool.replay(&transport));
+        assert_eq!(1, spool.len());
+        assert_eq!(1, spool.replay(&transport));
+        assert!(spool.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejected_results_are_kept_aside() {
+        let dir = spool_dir("rejected");
+        let spool = Spool::open(&dir, 1_000_000, Duration::from_secs(3600)).unwrap();
+        spool.write(&result("rejected")).unwrap();
+        spool.write(&result("sent")).unwrap();
+
+        let transport = MemoryTransport::new();
+        transport.reject_results(1);
+        assert_eq!(1, spool.replay(&transport));
+        assert_eq!("sent", transport.results()[0].name);
+        assert!(spool.is_empty());
+        assert!(dir.join(format!("{:020}.{}", 0, REJECTED_EXTENSION)).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejected_results_count_and_expire() {
+        let dir = spool_dir("rejected-expire");
+        let spool = Spool::open(&dir, 1_000_000, Duration::from_secs(3600)).unwrap();
+        spool.write(&result("rejected")).unwrap();
+        let size = spool.size().unwrap();
+        let transport = MemoryTransport::new();
+        transport.reject_results(1);
+        assert_eq!(0, spool.replay(&transport));
+        assert!(spool.is_empty());
+        assert_eq!(size, spool.size().unwrap());
+        // Not overwritten by the next result after re-opening.
+        let spool = Spool::open(&dir, 1_000_000, Duration::from_secs(3600)).unwrap();
+        spool.write(&result("next")).unwrap();
+        assert_eq!(1, spool.entries().unwrap()[0].0);
+
+        let rejected = dir.join(format!("{:020}.{}", 0, REJECTED_EXTENSION));
+        File::options().write(true).open(&rejected).unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(7200)).unwrap();
+        spool.replay(&transport);
+        assert!(!rejected.exists());
+        assert_eq!(0, spool.size().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn partial_writes_are_removed_on_open() {
+        let dir = spool_dir("partial");
+        fs::create_dir_all(&dir).unwrap();
+        let partial = dir.join(format!("{:020}.{}", 0, TMP_EXTENSION));
+        fs::write(&partial, "{\"scheduledAt\":").unwrap();
+
+        let spool = Spool::open(&dir, 1_000_000, Duration::from_secs(3600)).unwrap();
+        assert!(!partial.exists());
+        assert!(spool.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn size_is_bounded() {
+        let dir = spool_dir("size");
+        let spool = Spool::open(&dir, 1_000, Duration::from_secs(3600)).unwrap();
+        let mut written = 0;
+        while spool.write(&result("check")).is_ok() {
+            written += 1;
+        }
+        assert!(written > 0);
+        assert_eq!(written, spool.len());
+        assert!(spool.size().unwrap() <= 1_000);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn expired_results_are_discarded() {
+        let dir = spool_dir("expired");
+        let spool = Spool::open(&dir, 1_000_000, Duration::from_secs(3600)).unwrap();
+        let mut expired = result("expired");
+        expired.completed_at = Utc::now() - OldDuration::hours(2);
+        spool.write(&expired).unwrap();
+        spool.write(&result("fresh")).unwrap();
+
+        let transport = MemoryTransport::new();
+        assert_eq!(1, spool.replay(&transport));
+        assert_eq!("fresh", transport.results()[0].name);
+        assert!(spool.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
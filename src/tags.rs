@@ -0,0 +1,69 @@
+//! Matching of check tags against the client's tags.
+//! A check is only run if one of its tags matches one of the client's tags, and none matches an
+//! exclude tag, so that checks misrouted to the client are not run.
+//! The client's tags may be wildcard patterns, see [crate::wildcard].
+//! Checks without tags match none of the client's tags, so are always rejected.
+
+use crate::wildcard;
+
+
+/// Whether the check's tags match the client's `include` tags and none of its `exclude` tags.
+/// Otherwise returns the reason the check was rejected.
+pub fn accept(include: &[String], exclude: &[String], tags: &[String]) -> Result<(), String> {
+    if let Some((tag, pattern)) = find_match(exclude, tags) {
+        return Err(format!("Check tag `{}` is excluded by `{}`.", tag, pattern));
+    }
+    if find_match(include, tags).is_none() {
+        return Err(format!("Check tags [{}] do not match the client's tags [{}].", tags.join(", "), include.join(", ")));
+    }
+    Ok(())
+}
+
+/// The client's tags to register with the monitoring service, which routes checks by exact tag.
+/// Wildcard patterns only filter the checks received, so are left out.
+pub fn registered(include: &[String]) -> Vec<&String> {
+    include.iter()
+        .filter(|tag| !wildcard::is_pattern(tag))
+        .collect()
+}
+
+/// The first tag matching one of the patterns, along with the pattern.
+fn find_match<'a>(patterns: &'a [String], tags: &'a [String]) -> Option<(&'a str, &'a str)> {
+    tags.iter()
+        .flat_map(|tag| patterns.iter().map(move |pattern| (tag.as_str(), pattern.as_str())))
+        .find(|(tag, pattern)| wildcard::matches(pattern, tag))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn strings(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn accepts_matching_tags() {
+        let include = strings(&["web", "db-*"]);
+        assert!(accept(&include, &[], &strings(&["linux", "web"])).is_ok());
+        assert!(accept(&include, &[], &strings(&["db-prod"])).is_ok());
+        assert!(accept(&include, &[], &strings(&["linux"])).is_err());
+        assert!(accept(&include, &[], &[]).is_err());
+    }
+
+    #[test]
+    fn registers_plain_tags() {
+        let include = strings(&["web", "db-*", "linux", "rack-?"]);
+        assert_eq!(vec!["web", "linux"], registered(&include));
+    }
+
+    #[test]
+    fn excludes_take_precedence() {
+        let include = strings(&["db-*"]);
+        let exclude = strings(&["*-staging"]);
+        assert!(accept(&include, &exclude, &strings(&["db-prod"])).is_ok());
+        let reason = accept(&include, &exclude, &strings(&["db-prod", "db-staging"])).unwrap_err();
+        assert_eq!("Check tag `db-staging` is excluded by `*-staging`.", reason);
+    }
+}
@@ -1,22 +1,243 @@
-//! Constants and helpers for `timeout` functionality which
-//! varies depending on OS and toolchain.
-
-/// The `timeout` command.
-#[cfg(target_os = "macos")]
-pub static CMD: &str = "/usr/local/bin/gtimeout";  // brew install coreutils
-#[cfg(target_os = "linux")]
-pub static CMD: &str = "/usr/bin/timeout";
-
-/// The exit code `timeout` returns when the command times out.
-#[cfg(any(target_env = "gnu", target_env = ""))]
-pub static EXIT_CODE: i32 = 124;
-#[cfg(target_env = "musl")]  // For Alpine Linux.
-pub static EXIT_CODE: i32 = 143;
-
-/// Generate the array of arguments for the `timeout` command.
-pub fn opts(timeout_seconds: usize) -> Vec<String> {
-    #[cfg(any(target_env = "gnu", target_env = ""))]
-    return vec!["--signal".to_string(), "TERM".to_string(), format!("{}s", timeout_seconds)];
-    #[cfg(target_env = "musl")]  // For Alpine Linux.
-    return vec!["-s".to_string(), "TERM".to_string(), "-t".to_string(), format!("{}", timeout_seconds)];
+//! Run commands with a time-out.
+//! The command is started in its own process group so that the time-out applies to
+//! everything it spawns, eg. both sides of a pipe.  When the deadline passes the
+//! group is sent `SIGTERM`, followed by `SIGKILL` if it is still running after a grace period.
+//!
+//! Commands are run asynchronously on the runtime's reactor, so waiting on them does not tie up a thread.
+
+use std::io;
+use std::mem;
+use std::os::unix::process::CommandExt as _;
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll};
+use futures::future::{self, Either};
+use log::{debug, error, warn};
+use tokio::io::AsyncRead;
+use tokio::timer::Delay;
+use tokio_process::CommandExt;
+
+
+/// How long a timed-out command has to exit after `SIGTERM` before it is killed.
+pub const KILL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// The result of a command run with [run].
+#[derive(Debug)]
+pub struct Output {
+    pub status: ExitStatus,
+    /// Up to the first `max_output` bytes written to `stdout`.
+    pub stdout: Vec<u8>,
+    /// The total number of bytes written to `stdout`.
+    pub stdout_bytes: usize,
+    /// Up to the first `max_output` bytes written to `stderr`.
+    pub stderr: Vec<u8>,
+    /// The total number of bytes written to `stderr`.
+    pub stderr_bytes: usize,
+    /// The command did not exit before its time-out and was terminated.
+    pub timed_out: bool,
+}
+
+pub type OutputFuture = Box<dyn Future<Item = Output, Error = io::Error> + Send>;
+
+/// Run the command in a new process group, terminating the group if it does not exit in time.
+/// At most `max_output` bytes of each stream are kept, the rest is read and discarded.
+/// `on_spawn` is called with the process ID, which is also the process group ID, once started.
+///
+/// The command is spawned when the future is first polled, which must be on a tokio runtime.
+pub fn run<F>(mut command: Command, timeout: Duration, max_output: usize, on_spawn: F) -> OutputFuture
+    where F: FnOnce(u32) + Send + 'static
+{
+    Box::new(future::lazy(move || {
+        let deadline = Instant::now() + timeout;
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setpgid(0, 0) == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+            });
+        }
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn_async()?;
+        let pid = child.id();
+        on_spawn(pid);
+
+        let output = ReadPipe::new(child.stdout().take(), max_output)
+            .join(ReadPipe::new(child.stderr().take(), max_output));
+        let status = wait(child, pid, deadline);
+        // The output is read while waiting, so the command never blocks on a full pipe.
+        Ok::<_, io::Error>(status.select2(output).map_err(|e| e.split().0).and_then(move |res| match res {
+            Either::A(((status, timed_out), output)) =>
+                Either::A(collect(pid, output, deadline).map(move |output| (status, timed_out, output))),
+            Either::B((output, status)) =>
+                Either::B(status.map(move |(status, timed_out)| (status, timed_out, output))),
+        }))
+    })
+        .flatten()
+        .map(|(status, timed_out, ((stdout, stdout_bytes), (stderr, stderr_bytes)))| {
+            Output { status, stdout, stdout_bytes, stderr, stderr_bytes, timed_out }
+        }))
+}
+
+/// Send the signal to every process in the process group.
+/// Returns `false` if the group no longer exists.
+pub fn signal_group(pgid: u32, signal: libc::c_int) -> bool {
+    let res = unsafe { libc::killpg(pgid as libc::pid_t, signal) };
+    if res != 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ESRCH) {
+            error!("Failed to signal process group {}:  {}", pgid, err);
+        }
+        return false;
+    }
+    true
+}
+
+/// Wait for the command to exit, terminating its process group at the deadline.
+/// Yields the exit status and whether the command timed out.
+fn wait<F>(child: F, pid: u32, deadline: Instant) -> impl Future<Item = (ExitStatus, bool), Error = io::Error>
+    where F: Future<Item = ExitStatus, Error = io::Error>
+{
+    until(child, deadline).and_then(move |res| match res {
+        Ok(status) => Either::A(future::ok((status, false))),
+        Err(child) => {
+            warn!("Process group {} timed out, terminating.", pid);
+            signal_group(pid, libc::SIGTERM);
+            Either::B(until(child, Instant::now() + KILL_GRACE_PERIOD).and_then(move |res| match res {
+                Ok(status) => Either::A(future::ok((status, true))),
+                Err(child) => {
+                    warn!("Process group {} did not terminate, killing.", pid);
+                    signal_group(pid, libc::SIGKILL);
+                    Either::B(child.map(|status| (status, true)))
+                },
+            }))
+        },
+    })
+}
+
+/// Wait for the output pipes to be closed, killing the process group if they are held open past the deadline.
+/// Processes left running in the background may still hold the output pipes open.
+fn collect<F: Future>(pgid: u32, output: F, deadline: Instant) -> impl Future<Item = F::Item, Error = F::Error> {
+    until(output, deadline).and_then(move |res| match res {
+        Ok(output) => Either::A(future::ok(output)),
+        Err(output) => {
+            if signal_group(pgid, libc::SIGKILL) {
+                warn!("Killed background processes of process group {} holding its output open.", pgid);
+            }
+            Either::B(output)
+        },
+    })
+}
+
+/// Resolve with the future's item if it completes before the deadline,
+/// otherwise hand the future back so it can be waited on further.
+fn until<F: Future>(future: F, deadline: Instant) -> impl Future<Item = Result<F::Item, F>, Error = F::Error> {
+    future.select2(Delay::new(deadline)).then(|res| match res {
+        Ok(Either::A((item, _))) => Ok(Ok(item)),
+        Ok(Either::B((_, future))) => Ok(Err(future)),
+        Err(Either::A((e, _))) => Err(e),
+        Err(Either::B((e, future))) => {
+            error!("Timer failed, treating the deadline as passed:  {}", e);
+            Ok(Err(future))
+        },
+    })
+}
+
+/// Reads a pipe to the end, keeping up to `max_bytes`.
+/// Yields the kept bytes along with the total number of bytes read.
+struct ReadPipe<R> {
+    pipe: Option<R>,
+    max_bytes: usize,
+    buf: Vec<u8>,
+    total: usize,
+}
+
+impl<R: AsyncRead> ReadPipe<R> {
+    fn new(pipe: Option<R>, max_bytes: usize) -> Self {
+        Self { pipe, max_bytes, buf: Vec::new(), total: 0 }
+    }
+}
+
+impl<R: AsyncRead> Future for ReadPipe<R> {
+    type Item = (Vec<u8>, usize);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        if let Some(ref mut pipe) = self.pipe {
+            let mut chunk = [0u8; 8192];
+            loop {
+                match pipe.poll_read(&mut chunk) {
+                    Ok(Async::Ready(0)) => break,
+                    Ok(Async::Ready(n)) => {
+                        let keep = n.min(self.max_bytes.saturating_sub(self.buf.len()));
+                        self.buf.extend_from_slice(&chunk[..keep]);
+                        self.total += n;
+                    },
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        debug!("Error reading command output:  {}", e);
+                        break;
+                    },
+                }
+            }
+        }
+        self.pipe = None;
+        Ok(Async::Ready((mem::take(&mut self.buf), self.total)))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn shell(command: &str, timeout: Duration) -> Output {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(["-c", command]);
+        Runtime::new().unwrap().block_on(run(cmd, timeout, 1024, |_| {})).unwrap()
+    }
+
+    #[test]
+    fn exit_code_is_not_a_timeout() {
+        let output = shell("echo out && exit 124", Duration::from_secs(5));
+        assert!(!output.timed_out);
+        assert_eq!(Some(124), output.status.code());
+        assert_eq!(b"out\n".to_vec(), output.stdout);
+    }
+
+    #[test]
+    fn output_is_bounded() {
+        let output = shell("head -c 100000 /dev/zero", Duration::from_secs(5));
+        assert_eq!(1024, output.stdout.len());
+        assert_eq!(100_000, output.stdout_bytes);
+    }
+
+    #[test]
+    fn timeout_terminates_process_group() {
+        let started_at = Instant::now();
+        let output = shell("sleep 30 | sleep 30", Duration::from_secs(1));
+        assert!(output.timed_out);
+        assert!(started_at.elapsed() < Duration::from_secs(1) + KILL_GRACE_PERIOD);
+    }
+
+    #[test]
+    fn timeout_kills_after_grace_period() {
+        let started_at = Instant::now();
+        let output = shell("trap '' TERM; sleep 30", Duration::from_secs(1));
+        assert!(output.timed_out);
+        assert!(started_at.elapsed() >= Duration::from_secs(1) + KILL_GRACE_PERIOD);
+        assert!(started_at.elapsed() < Duration::from_secs(30));
+    }
+
+    #[test]
+    fn background_process_holding_output_is_killed() {
+        let started_at = Instant::now();
+        let output = shell("echo out; sleep 30 &", Duration::from_secs(1));
+        assert!(!output.timed_out);
+        assert_eq!(b"out\n".to_vec(), output.stdout);
+        assert!(started_at.elapsed() < Duration::from_secs(30));
+    }
 }
@@ -0,0 +1,205 @@
+//! In-memory queues, for running the consumer without AWS, eg. in tests.
+//! Received messages stay hidden until they are released or deleted;
+//! visibility time-outs are not simulated.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use futures::future;
+use tokio::timer::Delay;
+
+use crate::messages::check::ClientCheckResultMessage;
+use super::{CommandSource, ErrorKind, ReceiveError, ReceiveFuture, ReceivedMessage, ResultSink, SendFailures, TransportFuture};
+
+
+/// How long a receive waits when no messages are queued.
+const RECEIVE_WAIT_TIME: Duration = Duration::from_millis(50);
+
+#[derive(Default)]
+struct State {
+    next_id: usize,
+    /// Messages waiting to be received.
+    queued: VecDeque<ReceivedMessage>,
+    /// Received messages by receipt handle.
+    received: HashMap<String, ReceivedMessage>,
+    /// IDs of the deleted messages.
+    deleted: Vec<String>,
+    results: Vec<ClientCheckResultMessage>,
+    /// The kinds of error the next receives fail with.
+    failing_receives: VecDeque<ErrorKind>,
+    failing_deletes: usize,
+    failing_sends: usize,
+    /// The number of the next results rejected as invalid.
+    rejecting_results: usize,
+    /// How long each send takes.
+    send_delay: Duration,
+}
+
+#[derive(Default)]
+pub struct MemoryTransport {
+    state: Mutex<State>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a command message, returning its message ID.
+    pub fn push(&self, body: &str) -> String {
+        let mut state = self.state.lock().unwrap();
+        state.next_id += 1;
+        let message_id = format!("message-{}", state.next_id);
+        state.queued.push_back(ReceivedMessage {
+            message_id: message_id.clone(),
+            receipt_handle: String::new(),
+            body: body.to_string(),
+        });
+        message_id
+    }
+
+    /// Queue a received message to be received again, as SQS may deliver a message more than once.
+    /// Both deliveries stay received until each is released or deleted.
+    pub fn redeliver(&self, message_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        let message = state.received.values()
+            .find(|message| message.message_id == message_id)
+            .cloned();
+        if let Some(mut message) = message {
+            message.receipt_handle.clear();
+            state.queued.push_back(message);
+        }
+    }
+
+    /// The number of messages waiting to be received.
+    pub fn queued(&self) -> usize {
+        self.state.lock().unwrap().queued.len()
+    }
+
+    /// The number of messages received but neither released nor deleted.
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().received.len()
+    }
+
+    /// The IDs of the deleted messages, in the order they were deleted.
+    pub fn deleted(&self) -> Vec<String> {
+        self.state.lock().unwrap().deleted.clone()
+    }
+
+    /// The results sent, in the order they were sent.
+    pub fn results(&self) -> Vec<ClientCheckResultMessage> {
+        self.state.lock().unwrap().results.clone()
+    }
+
+    /// Fail the next `count` receives with a retryable error.
+    pub fn fail_receives(&self, count: usize) {
+        self.fail_receives_with(ErrorKind::Retryable, count);
+    }
+
+    /// Fail the next `count` receives with the kind of error.
+    pub fn fail_receives_with(&self, kind: ErrorKind, count: usize) {
+        self.state.lock().unwrap().failing_receives = vec![kind; count].into();
+    }
+
+    /// Fail the next `count` delete batches with a retryable error.
+    pub fn fail_deletes(&self, count: usize) {
+        self.state.lock().unwrap().failing_deletes = count;
+    }
+
+    /// Fail the next `count` send batches with a retryable error.
+    pub fn fail_sends(&self, count: usize) {
+        self.state.lock().unwrap().failing_sends = count;
+    }
+
+    /// Make each send take `delay`, as a slow result queue would.
+    pub fn delay_sends(&self, delay: Duration) {
+        self.state.lock().unwrap().send_delay = delay;
+    }
+
+    /// Reject the next `count` results sent, as SQS does batch entries failed by the sender's fault.
+    pub fn reject_results(&self, count: usize) {
+        self.state.lock().unwrap().rejecting_results = count;
+    }
+}
+
+impl CommandSource for MemoryTransport {
+    fn receive(&self, max_messages: usize, _visibility_timeout: Duration) -> ReceiveFuture {
+        let mut state = self.state.lock().unwrap();
+        if let Some(kind) = state.failing_receives.pop_front() {
+            return Box::new(future::err(ReceiveError::new(kind, format!("Receive failed:  {:?}", kind))));
+        }
+        if state.queued.is_empty() {
+            return Box::new(Delay::new(Instant::now() + RECEIVE_WAIT_TIME)
+                .then(|_| Ok(Vec::new())));
+        }
+        let count = max_messages.min(state.queued.len());
+        let mut messages: Vec<ReceivedMessage> = state.queued.drain(..count).collect();
+        for message in messages.iter_mut() {
+            state.next_id += 1;
+            message.receipt_handle = format!("receipt-{}", state.next_id);
+            state.received.insert(message.receipt_handle.clone(), message.clone());
+        }
+        Box::new(future::ok(messages))
+    }
+
+    fn extend_visibility(&self, entries: Vec<(String, Duration)>) -> TransportFuture<Vec<usize>> {
+        let state = self.state.lock().unwrap();
+        let extended = entries.iter()
+            .enumerate()
+            .filter(|(_, (receipt_handle, _))| state.received.contains_key(receipt_handle))
+            .map(|(index, _)| index)
+            .collect();
+        Box::new(future::ok(extended))
+    }
+
+    fn release(&self, receipt_handles: Vec<String>) -> TransportFuture<()> {
+        let mut state = self.state.lock().unwrap();
+        for receipt_handle in receipt_handles.iter() {
+            if let Some(mut message) = state.received.remove(receipt_handle) {
+                message.receipt_handle.clear();
+                state.queued.push_front(message);
+            }
+        }
+        Box::new(future::ok(()))
+    }
+
+    fn delete(&self, receipt_handles: &[String]) -> Vec<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.failing_deletes > 0 {
+            state.failing_deletes -= 1;
+            return (0..receipt_handles.len()).collect();
+        }
+        for receipt_handle in receipt_handles {
+            if let Some(message) = state.received.remove(receipt_handle) {
+                state.deleted.push(message.message_id);
+            }
+        }
+        Vec::new()
+    }
+}
+
+impl ResultSink for MemoryTransport {
+    fn send(&self, results: &[ClientCheckResultMessage]) -> SendFailures {
+        let send_delay = self.state.lock().unwrap().send_delay;
+        thread::sleep(send_delay);
+        let mut state = self.state.lock().unwrap();
+        let mut failures = SendFailures::default();
+        if state.failing_sends > 0 {
+            state.failing_sends -= 1;
+            failures.retryable = (0..results.len()).collect();
+            return failures;
+        }
+        for (index, result) in results.iter().enumerate() {
+            if state.rejecting_results > 0 {
+                state.rejecting_results -= 1;
+                failures.rejected.push((index, String::from("InvalidMessageContents Invalid result.")));
+            } else {
+                state.results.push(result.clone());
+            }
+        }
+        failures
+    }
+}
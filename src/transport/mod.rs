@@ -0,0 +1,119 @@
+//! The queues the client receives check commands from and sends results to.
+//! In production both are SQS queues, see [sqs::SqsTransport].
+//! [memory::MemoryTransport] keeps the queues in memory so that the receive, execute
+//! and delete flow can be exercised without AWS.
+
+pub mod memory;
+pub mod sqs;
+
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+use futures::Future;
+
+use crate::messages::check::ClientCheckResultMessage;
+
+
+pub type TransportError = Box<dyn Error + Send + Sync>;
+pub type TransportFuture<T> = Box<dyn Future<Item = T, Error = TransportError> + Send>;
+pub type ReceiveFuture = Box<dyn Future<Item = Vec<ReceivedMessage>, Error = ReceiveError> + Send>;
+
+/// How a failed receive should be handled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    /// Likely to succeed if retried later, eg. throttling or a network error.
+    Retryable,
+    /// The queue no longer exists, so the client must register again to be given a new one.
+    QueueMissing,
+    /// Will keep failing, eg. access denied.
+    Fatal,
+}
+
+#[derive(Debug)]
+pub struct ReceiveError {
+    pub kind: ErrorKind,
+    pub error: TransportError,
+}
+
+impl ReceiveError {
+    pub fn new<E: Into<TransportError>>(kind: ErrorKind, error: E) -> Self {
+        Self { kind, error: error.into() }
+    }
+}
+
+impl fmt::Display for ReceiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl Error for ReceiveError { }
+
+/// A command message received from the command queue.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReceivedMessage {
+    pub message_id: String,
+    /// Identifies this receipt of the message, for extending its visibility or deleting it.
+    pub receipt_handle: String,
+    pub body: String,
+}
+
+/// The queue of check commands.
+/// Received messages are hidden from other clients until their visibility time-out expires,
+/// and must be deleted once their check has completed.
+pub trait CommandSource: Send + Sync {
+    /// Receive up to `max_messages`, hidden for `visibility_timeout`.
+    /// When none are available, waits a while for messages to arrive before yielding none.
+    fn receive(&self, max_messages: usize, visibility_timeout: Duration) -> ReceiveFuture;
+
+    /// Hide the messages, given by receipt handle, for the given time from now.
+    /// Yields the indexes of the entries which were extended.
+    fn extend_visibility(&self, entries: Vec<(String, Duration)>) -> TransportFuture<Vec<usize>>;
+
+    /// Make the messages immediately visible again so they can be picked up by another client.
+    fn release(&self, receipt_handles: Vec<String>) -> TransportFuture<()>;
+
+    /// Delete the processed messages.
+    /// Called from a batching thread, so blocks until done.
+    /// Returns the indexes of the receipt handles which should be retried.
+    fn delete(&self, receipt_handles: &[String]) -> Vec<usize>;
+}
+
+/// The results of a send which were not sent.
+#[derive(Debug, Default, PartialEq)]
+pub struct SendFailures {
+    /// Indexes of the results which failed with a retryable error.
+    pub retryable: Vec<usize>,
+    /// Indexes of the results which will never be accepted, eg. as invalid, along with the reason.
+    pub rejected: Vec<(usize, String)>,
+}
+
+impl SendFailures {
+    pub fn is_empty(&self) -> bool {
+        self.retryable.is_empty() && self.rejected.is_empty()
+    }
+
+    pub fn extend(&mut self, other: SendFailures) {
+        self.retryable.extend(other.retryable);
+        self.rejected.extend(other.rejected);
+    }
+
+    /// Whether the result was sent.
+    pub fn sent(&self, index: usize) -> bool {
+        !self.retryable.contains(&index) && self.rejected(index).is_none()
+    }
+
+    /// The reason the result was rejected, if it was.
+    pub fn rejected(&self, index: usize) -> Option<&str> {
+        self.rejected.iter().find(|(i, _)| *i == index).map(|(_, reason)| reason.as_str())
+    }
+}
+
+/// The queue of check results.
+pub trait ResultSink: Send + Sync {
+    /// Send the results.
+    /// Called from a batching thread, so blocks until done.
+    /// Returns the results which should be retried, and those rejected which never will be accepted.
+    fn send(&self, results: &[ClientCheckResultMessage]) -> SendFailures;
+}
@@ -0,0 +1,410 @@
+//! SQS queues, as registered with the monitoring service.
+//! A new registration may switch to new queues while messages received from the old command queue
+//! are still in flight, so the queue each message came from is kept along with its receipt handle.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use futures::future;
+use log::{debug, error, info, warn};
+use rusoto_core::RusotoError;
+use rusoto_sqs::{
+    SqsClient, Sqs,
+    BatchResultErrorEntry,
+    ChangeMessageVisibilityBatchRequest, ChangeMessageVisibilityBatchRequestEntry,
+    ChangeMessageVisibilityRequest,
+    DeleteMessageBatchRequest, DeleteMessageBatchRequestEntry,
+    ReceiveMessageError, ReceiveMessageRequest,
+    SendMessageBatchRequest, SendMessageBatchRequestEntry,
+};
+
+use crate::batch::{MAX_BATCH_PAYLOAD, MAX_BATCH_SIZE};
+use crate::messages::check::{ClientCheckResultMessage, MAX_MESSAGE_SIZE};
+use super::{CommandSource, ErrorKind, ReceiveError, ReceiveFuture, ReceivedMessage, ResultSink, SendFailures, TransportFuture};
+
+
+/// How long a receive waits for messages to arrive, in seconds.  20 seconds is the maximum.
+const RECEIVE_WAIT_TIME: i64 = 20;
+/// Error codes returned for a queue which does not exist.
+const QUEUE_MISSING_CODES: &[&str] = &["AWS.SimpleQueueService.NonExistentQueue", "QueueDoesNotExist"];
+/// Error codes of client errors which are worth retrying.
+const RETRYABLE_CODES: &[&str] = &[
+    "Throttling", "ThrottlingException", "RequestThrottled", "RequestLimitExceeded",
+    "ExpiredToken", "RequestExpired", "RequestTimeout",
+];
+/// How long a receipt handle can be used for, as SQS caps a message's visibility at 12 hours from its receipt.
+const RECEIPT_LIFETIME: Duration = Duration::from_secs(43_200);
+
+struct Queues {
+    command: String,
+    result: String,
+}
+
+/// The command queue a message was received from, by receipt handle, along with when it was received.
+type Receipts = HashMap<String, (String, Instant)>;
+
+pub struct SqsTransport {
+    sqs_client: SqsClient,
+    queues: RwLock<Queues>,
+    receipts: Arc<Mutex<Receipts>>,
+}
+
+impl SqsTransport {
+    pub fn new(sqs_client: SqsClient, command_queue: String, result_queue: String) -> Self {
+        Self {
+            sqs_client,
+            queues: RwLock::new(Queues { command: command_queue, result: result_queue }),
+            receipts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Switch to the queues given by a new registration.
+    /// Messages already received are still extended, released and deleted on the queue they came from.
+    pub fn set_queues(&self, command_queue: String, result_queue: String) {
+        let mut queues = self.queues.write().unwrap();
+        if queues.command != command_queue || queues.result != result_queue {
+            info!("Switching to command queue {} and result queue {}.", command_queue, result_queue);
+        }
+        *queues = Queues { command: command_queue, result: result_queue };
+    }
+
+    fn command_queue(&self) -> String {
+        self.queues.read().unwrap().command.clone()
+    }
+
+    fn result_queue(&self) -> String {
+        self.queues.read().unwrap().result.clone()
+    }
+
+    /// The entries grouped by the command queue their receipt handle came from, see [by_queue].
+    fn by_queue<T, F>(&self, entries: Vec<T>, receipt_handle: F) -> Vec<(String, Vec<(usize, T)>)>
+        where F: Fn(&T) -> &str
+    {
+        by_queue(&self.receipts.lock().unwrap(), &self.command_queue(), entries, receipt_handle)
+    }
+
+    /// Forget the receipt handles, once their messages are deleted or released.
+    fn forget<'a, I: IntoIterator<Item = &'a String>>(&self, receipt_handles: I) {
+        let mut receipts = self.receipts.lock().unwrap();
+        for receipt_handle in receipt_handles {
+            receipts.remove(receipt_handle);
+        }
+    }
+}
+
+impl CommandSource for SqsTransport {
+    fn receive(&self, max_messages: usize, visibility_timeout: Duration) -> ReceiveFuture {
+        let command_queue = self.command_queue();
+        let receipts = self.receipts.clone();
+        // Messages neither deleted nor released, eg. left on the queue, are forgotten once their receipt handles expire.
+        receipts.lock().unwrap().retain(|_, (_, received_at)| received_at.elapsed() < RECEIPT_LIFETIME);
+        let req = ReceiveMessageRequest {
+            attribute_names: None,
+            max_number_of_messages: Some(max_messages.min(MAX_BATCH_SIZE) as i64),
+            message_attribute_names: None,
+            queue_url: command_queue.clone(),
+            receive_request_attempt_id: None,  // Only valid for FIFO queues.
+            visibility_timeout: Some(visibility_timeout.as_secs() as i64),
+            wait_time_seconds: Some(RECEIVE_WAIT_TIME),
+        };
+        Box::new(self.sqs_client.receive_message(req)
+            .map(move |res| {
+                let mut receipts = receipts.lock().unwrap();
+                res.messages.unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|message| match (message.message_id, message.receipt_handle, message.body) {
+                        (Some(message_id), Some(receipt_handle), Some(body)) => {
+                            receipts.insert(receipt_handle.clone(), (command_queue.clone(), Instant::now()));
+                            Some(ReceivedMessage { message_id, receipt_handle, body })
+                        },
+                        _ => {
+                            error!("Ignoring incomplete message received from the command queue.");
+                            None
+                        },
+                    })
+                    .collect()
+            })
+            .map_err(receive_error))
+    }
+
+    fn extend_visibility(&self, entries: Vec<(String, Duration)>) -> TransportFuture<Vec<usize>> {
+        // The batch entry IDs are the indexes of the entries, across the requests to each queue.
+        let requests: Vec<_> = self.by_queue(entries, |(receipt_handle, _)| receipt_handle)
+            .into_iter()
+            .map(|(queue_url, entries)| {
+                let req = ChangeMessageVisibilityBatchRequest {
+                    entries: entries.into_iter()
+                        .map(|(index, (receipt_handle, timeout))| ChangeMessageVisibilityBatchRequestEntry {
+                            id: index.to_string(),
+                            receipt_handle,
+                            visibility_timeout: Some(timeout.as_secs() as i64),
+                        })
+                        .collect(),
+                    queue_url,
+                };
+                self.sqs_client.change_message_visibility_batch(req)
+                    .map(|res| {
+                        for entry in res.failed.iter() {
+                            warn!("Failed to extend visibility of batch entry {}:  {} {}",
+                                  entry.id, entry.code, entry.message.as_deref().unwrap_or(""));
+                        }
+                        res.successful.iter()
+                            .filter_map(|entry| entry.id.parse().ok())
+                            .collect::<Vec<usize>>()
+                    })
+            })
+            .collect();
+        Box::new(future::join_all(requests)
+            .map(|extended| extended.concat())
+            .map_err(|e| e.into()))
+    }
+
+    fn release(&self, receipt_handles: Vec<String>) -> TransportFuture<()> {
+        self.forget(&receipt_handles);
+        let queues = self.by_queue(receipt_handles, String::as_str);
+        let requests: Vec<_> = queues.into_iter()
+            .flat_map(|(queue_url, receipt_handles)| receipt_handles.into_iter()
+                .map(move |(_, receipt_handle)| (queue_url.clone(), receipt_handle)))
+            .map(|(queue_url, receipt_handle)| {
+                let req = ChangeMessageVisibilityRequest {
+                    queue_url,
+                    receipt_handle,
+                    visibility_timeout: 0,
+                };
+                self.sqs_client.change_message_visibility(req).then(|res| {
+                    match res {
+                        Ok(_) => debug!("Released message back to the queue."),
+                        Err(e) => error!("Failed to release message back to the queue:  {}", e),
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        Box::new(future::join_all(requests).map(|_| ()))
+    }
+
+    fn delete(&self, receipt_handles: &[String]) -> Vec<usize> {
+        let mut failed = Vec::new();
+        for (queue_url, entries) in self.by_queue(receipt_handles.to_vec(), String::as_str) {
+            let (indexes, receipt_handles): (Vec<usize>, Vec<String>) = entries.into_iter().unzip();
+            let retry = delete_messages(&self.sqs_client, &queue_url, &receipt_handles);
+            self.forget(receipt_handles.iter().enumerate()
+                .filter(|(index, _)| !retry.contains(index))
+                .map(|(_, receipt_handle)| receipt_handle));
+            failed.extend(retry.into_iter().map(|index| indexes[index]));
+        }
+        failed
+    }
+}
+
+impl ResultSink for SqsTransport {
+    fn send(&self, results: &[ClientCheckResultMessage]) -> SendFailures {
+        send_results(&self.sqs_client, &self.result_queue(), results)
+    }
+}
+
+/// The entries grouped by the command queue their receipt handle came from, along with their indexes in `entries`.
+/// Receipt handles not known to have come from another queue are taken to be from `command_queue`.
+fn by_queue<T, F>(receipts: &Receipts, command_queue: &str, entries: Vec<T>, receipt_handle: F)
+                  -> Vec<(String, Vec<(usize, T)>)>
+    where F: Fn(&T) -> &str
+{
+    let mut queues: Vec<(String, Vec<(usize, T)>)> = Vec::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        let queue_url = receipts.get(receipt_handle(&entry))
+            .map_or(command_queue, |(queue_url, _)| queue_url.as_str());
+        match queues.iter_mut().find(|(url, _)| url == queue_url) {
+            Some((_, entries)) => entries.push((index, entry)),
+            None => queues.push((queue_url.to_string(), vec![(index, entry)])),
+        }
+    }
+    queues
+}
+
+/// Classify a failed receive by whether it is worth retrying.
+fn receive_error(e: RusotoError<ReceiveMessageError>) -> ReceiveError {
+    let kind = match e {
+        // The only service error is hitting the in-flight message limit.
+        RusotoError::Service(_) => ErrorKind::Retryable,
+        RusotoError::HttpDispatch(_) | RusotoError::Credentials(_) | RusotoError::ParseError(_) => ErrorKind::Retryable,
+        RusotoError::Validation(_) => ErrorKind::Fatal,
+        RusotoError::Unknown(ref res) => response_kind(res.status.as_u16(), &String::from_utf8_lossy(&res.body)),
+    };
+    ReceiveError::new(kind, e)
+}
+
+/// Classify an error response by its status and error code.
+/// Server errors and throttling are retried, while the other client errors, eg. access denied, are fatal.
+fn response_kind(status: u16, body: &str) -> ErrorKind {
+    let code = error_code(body).unwrap_or("");
+    if QUEUE_MISSING_CODES.contains(&code) {
+        ErrorKind::QueueMissing
+    } else if status >= 500 || status == 429 || RETRYABLE_CODES.contains(&code) {
+        ErrorKind::Retryable
+    } else {
+        ErrorKind::Fatal
+    }
+}
+
+/// The error code of an XML error response.
+fn error_code(body: &str) -> Option<&str> {
+    let start = body.find("<Code>")? + "<Code>".len();
+    let end = start + body[start..].find("</Code>")?;
+    Some(body[start..end].trim())
+}
+
+/// Send the check results to the result queue with `SendMessageBatch`.
+/// Returns the results which should be retried, and those rejected.
+fn send_results(sqs_client: &SqsClient, queue: &str, messages: &[ClientCheckResultMessage]) -> SendFailures {
+    let mut failed = SendFailures::default();
+    let mut entries: Vec<SendMessageBatchRequestEntry> = Vec::new();
+    let mut payload = 0;
+    for (index, message) in messages.iter().enumerate() {
+        let message_body = match message.to_json(MAX_MESSAGE_SIZE) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Failed to serialize result message:  {}", e);
+                failed.rejected.push((index, format!("Failed to serialize:  {}", e)));
+                continue;
+            },
+        };
+        // Keep the request within the SQS total payload limit.
+        if !entries.is_empty() && payload + message_body.len() > MAX_BATCH_PAYLOAD {
+            failed.extend(send_batch(sqs_client, queue, entries.split_off(0)));
+            payload = 0;
+        }
+        payload += message_body.len();
+        entries.push(SendMessageBatchRequestEntry {
+            delay_seconds: None,
+            id: index.to_string(),
+            message_attributes: None,
+            message_body,
+            message_deduplication_id: None,  // Only valid for FIFO queues.
+            message_group_id: None,  // Only valid for FIFO queues.
+        });
+    }
+    if !entries.is_empty() {
+        failed.extend(send_batch(sqs_client, queue, entries));
+    }
+    failed
+}
+
+fn send_batch(sqs_client: &SqsClient, queue: &str, entries: Vec<SendMessageBatchRequestEntry>) -> SendFailures {
+    let ids: Vec<String> = entries.iter().map(|e| e.id.clone()).collect();
+    let req = SendMessageBatchRequest {
+        entries,
+        queue_url: queue.to_string(),
+    };
+    match sqs_client.send_message_batch(req).sync() {
+        Ok(res) => {
+            for entry in res.successful.iter() {
+                debug!("Sent message to result queue:  {}", entry.message_id);
+            }
+            batch_failures("send", &res.failed)
+        },
+        Err(e) => {
+            error!("Failed to send messages to result queue:  {}", e);
+            SendFailures {
+                retryable: ids.iter().filter_map(|id| id.parse().ok()).collect(),
+                rejected: Vec::new(),
+            }
+        },
+    }
+}
+
+/// Delete the processed messages from the queue with `DeleteMessageBatch`.
+/// Returns the indexes of the receipt handles which should be retried.
+fn delete_messages(sqs_client: &SqsClient, queue: &str, receipt_handles: &[String]) -> Vec<usize> {
+    let req = DeleteMessageBatchRequest {
+        entries: receipt_handles.iter()
+            .enumerate()
+            .map(|(index, receipt_handle)| DeleteMessageBatchRequestEntry {
+                id: index.to_string(),
+                receipt_handle: receipt_handle.clone(),
+            })
+            .collect(),
+        queue_url: queue.to_string(),
+    };
+    match sqs_client.delete_message_batch(req).sync() {
+        Ok(res) => {
+            debug!("Deleted {} message(s).", res.successful.len());
+            // A receipt handle the queue rejects will never be deleted, so is not retried.
+            batch_failures("delete", &res.failed).retryable
+        },
+        Err(e) => {
+            error!("Error deleting messages:  {}", e);
+            (0..receipt_handles.len()).collect()
+        },
+    }
+}
+
+/// Log the failed batch entries, separating those worth retrying from those rejected.
+/// Entries failed due to the sender's fault will fail again, so are rejected rather than retried.
+fn batch_failures(operation: &str, failed: &[BatchResultErrorEntry]) -> SendFailures {
+    let mut failures = SendFailures::default();
+    for entry in failed {
+        let index = match entry.id.parse() {
+            Ok(index) => index,
+            Err(_) => continue,
+        };
+        let message = entry.message.as_deref().unwrap_or("");
+        if entry.sender_fault {
+            error!("Failed to {} batch entry {}:  {} {}", operation, entry.id, entry.code, message);
+            failures.rejected.push((index, format!("{} {}", entry.code, message).trim_end().to_string()));
+        } else {
+            warn!("Failed to {} batch entry {}, will retry:  {} {}", operation, entry.id, entry.code, message);
+            failures.retryable.push(index);
+        }
+    }
+    failures
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn error_response(code: &str) -> String {
+        format!("<?xml version=\"1.0\"?><ErrorResponse xmlns=\"http://queue.amazonaws.com/doc/2012-11-05/\">\
+                 <Error><Type>Sender</Type><Code>{}</Code><Message>Error</Message><Detail/></Error>\
+                 <RequestId>d0b1b9c2-0000-0000-0000-000000000000</RequestId></ErrorResponse>", code)
+    }
+
+    #[test]
+    fn receipt_handles_keep_their_queue() {
+        let mut receipts = Receipts::new();
+        receipts.insert(String::from("old-1"), (String::from("https://old"), Instant::now()));
+        receipts.insert(String::from("old-2"), (String::from("https://old"), Instant::now()));
+        receipts.insert(String::from("new-1"), (String::from("https://new"), Instant::now()));
+        let entries = vec!["old-1", "new-1", "unknown", "old-2"];
+
+        let queues = by_queue(&receipts, "https://new", entries, |receipt_handle| receipt_handle);
+
+        assert_eq!(vec![
+            (String::from("https://old"), vec![(0, "old-1"), (3, "old-2")]),
+            (String::from("https://new"), vec![(1, "new-1"), (2, "unknown")]),
+        ], queues);
+    }
+
+    #[test]
+    fn missing_queue() {
+        let body = error_response("AWS.SimpleQueueService.NonExistentQueue");
+        assert_eq!(ErrorKind::QueueMissing, response_kind(400, &body));
+    }
+
+    #[test]
+    fn throttling_and_server_errors_are_retryable() {
+        assert_eq!(ErrorKind::Retryable, response_kind(400, &error_response("RequestThrottled")));
+        assert_eq!(ErrorKind::Retryable, response_kind(503, &error_response("ServiceUnavailable")));
+        assert_eq!(ErrorKind::Retryable, response_kind(500, "Internal error"));
+    }
+
+    #[test]
+    fn other_client_errors_are_fatal() {
+        assert_eq!(ErrorKind::Fatal, response_kind(403, &error_response("AccessDenied")));
+        assert_eq!(ErrorKind::Fatal, response_kind(403, &error_response("InvalidClientTokenId")));
+        assert_eq!(ErrorKind::Fatal, response_kind(400, ""));
+    }
+}
@@ -0,0 +1,158 @@
+//! Visibility time-out heartbeat.
+//! Command messages are received with a fixed visibility time-out, which a check's own
+//! time-out may exceed.  The heartbeat extends the visibility of the in-flight messages
+//! before it expires so they are not redelivered to another client while still running,
+//! or until deleted once they have completed.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{stream, Future, Stream};
+use futures::sync::oneshot;
+use log::{debug, error};
+use tokio::runtime::TaskExecutor;
+use tokio::timer::Interval;
+
+use crate::batch::MAX_BATCH_SIZE;
+use crate::in_flight::{InFlight, InFlightCheck};
+use crate::transport::CommandSource;
+
+
+/// How often the in-flight messages are inspected.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// Messages whose visibility expires within this period are extended.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+/// Extra visibility on top of the check's remaining run time,
+/// covering the time needed to publish its result and delete the message.
+const VISIBILITY_MARGIN: Duration = Duration::from_secs(60);
+/// The maximum visibility time-out SQS allows.
+const MAX_VISIBILITY_TIMEOUT: u64 = 43_200;
+
+pub struct VisibilityHeartbeat {
+    stop: Mutex<Option<oneshot::Sender<()>>>,
+    stopped: Mutex<Option<oneshot::Receiver<()>>>,
+}
+
+impl VisibilityHeartbeat {
+    /// Start extending the visibility of the in-flight messages from the source,
+    /// as a task on the runtime.
+    pub fn start(executor: &TaskExecutor, source: Arc<dyn CommandSource>, in_flight: Arc<InFlight>) -> Self {
+        let (stop, stop_rx) = oneshot::channel::<()>();
+        let (stopped_tx, stopped) = oneshot::channel::<()>();
+        let heartbeat = Interval::new(Instant::now() + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL)
+            .map_err(|e| error!("Visibility heartbeat timer failed:  {}", e))
+            .for_each(move |_| extend_expiring(&source, &in_flight));
+        executor.spawn(heartbeat.select2(stop_rx).then(move |_| {
+            let _ = stopped_tx.send(());
+            Ok(())
+        }));
+        Self {
+            stop: Mutex::new(Some(stop)),
+            stopped: Mutex::new(Some(stopped)),
+        }
+    }
+
+    /// Stop the heartbeat, blocking until it has stopped.
+    /// Must not be called from a runtime thread.
+    pub fn stop(&self) {
+        self.stop.lock().unwrap().take();
+        if let Some(stopped) = self.stopped.lock().unwrap().take() {
+            if stopped.wait().is_err() {
+                error!("Visibility heartbeat terminated abnormally.");
+            }
+        }
+    }
+}
+
+/// Extend the visibility of the messages about to become visible again.
+fn extend_expiring(source: &Arc<dyn CommandSource>, in_flight: &Arc<InFlight>)
+                   -> impl Future<Item = (), Error = ()>
+{
+    let now = Instant::now();
+    let batches: Vec<Vec<InFlightCheck>> = in_flight.expiring_before(now + EXPIRY_MARGIN)
+        .chunks(MAX_BATCH_SIZE)
+        .map(<[InFlightCheck]>::to_vec)
+        .collect();
+    let source = source.clone();
+    let in_flight = in_flight.clone();
+    stream::iter_ok(batches).for_each(move |checks| {
+        let timeouts: Vec<Duration> = checks.iter()
+            .map(|check| Duration::from_secs(visibility_timeout(check, now)))
+            .collect();
+        let entries = checks.iter()
+            .zip(timeouts.iter())
+            .map(|(check, timeout)| (check.receipt_handle.clone(), *timeout))
+            .collect();
+        let in_flight = in_flight.clone();
+        source.extend_visibility(entries).then(move |res| {
+            match res {
+                Ok(extended) => {
+                    for index in extended {
+                        let check = &checks[index];
+                        debug!("Extended visibility of message {} by {} seconds.", check.message_id, timeouts[index].as_secs());
+                        in_flight.set_visible_until(&check.receipt_handle, now + timeouts[index]);
+                    }
+                },
+                Err(e) => error!("Failed to extend message visibility:  {}", e),
+            }
+            Ok(())
+        })
+    })
+}
+
+/// The visibility time-out, in seconds, needed to cover the rest of the check's run.
+/// Checks which have not started yet are given their full time-out once they do,
+/// so until then the standard margin applies, as it does to completed checks waiting for their message to be deleted.
+fn visibility_timeout(check: &InFlightCheck, now: Instant) -> u64 {
+    let remaining = match check.started {
+        Some((started_at, timeout)) => (started_at + timeout).saturating_duration_since(now),
+        None => Duration::from_secs(0),
+    };
+    (remaining + VISIBILITY_MARGIN).as_secs().min(MAX_VISIBILITY_TIMEOUT)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn in_flight_check(started: Option<(Instant, Duration)>) -> InFlightCheck {
+        InFlightCheck {
+            message_id: String::from("50aa8ce2-2ba9-5a30-a2b9-d88aa7418f2b"),
+            receipt_handle: String::from("receipt"),
+            pid: None,
+            visible_until: Instant::now(),
+            started,
+            completed: false,
+        }
+    }
+
+    #[test]
+    fn visibility_covers_remaining_run_time() {
+        let started_at = Instant::now();
+        let check = in_flight_check(Some((started_at, Duration::from_secs(600))));
+        let now = started_at + Duration::from_secs(100);
+        assert_eq!(500 + VISIBILITY_MARGIN.as_secs(), visibility_timeout(&check, now));
+    }
+
+    #[test]
+    fn visibility_of_overdue_check() {
+        let started_at = Instant::now();
+        let check = in_flight_check(Some((started_at, Duration::from_secs(600))));
+        let now = started_at + Duration::from_secs(700);
+        assert_eq!(VISIBILITY_MARGIN.as_secs(), visibility_timeout(&check, now));
+    }
+
+    #[test]
+    fn visibility_of_pending_check() {
+        let check = in_flight_check(None);
+        assert_eq!(VISIBILITY_MARGIN.as_secs(), visibility_timeout(&check, Instant::now()));
+    }
+
+    #[test]
+    fn visibility_is_capped() {
+        let now = Instant::now();
+        let check = in_flight_check(Some((now, Duration::from_secs(86_400))));
+        assert_eq!(MAX_VISIBILITY_TIMEOUT, visibility_timeout(&check, now));
+    }
+}
@@ -0,0 +1,74 @@
+//! Wildcard patterns, where `*` matches any characters, including none, and `?` any one character.
+//! Every other character matches only itself.
+
+
+/// Whether the text has wildcards, rather than only matching itself.
+pub fn is_pattern(text: &str) -> bool {
+    text.contains(['*', '?'])
+}
+
+/// Whether the path matches the wildcard pattern, component by component,
+/// so that `*` and `?` do not match `/`.
+pub fn matches_path(pattern: &str, path: &str) -> bool {
+    let patterns: Vec<&str> = pattern.split('/').collect();
+    let components: Vec<&str> = path.split('/').collect();
+    patterns.len() == components.len()
+        && patterns.iter().zip(components).all(|(pattern, component)| matches(pattern, component))
+}
+
+/// Whether the text matches the wildcard pattern.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // The position of the last `*`, and of the text character it is currently matched up to.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Backtrack, letting the `*` match one more character.
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("web", "web"));
+        assert!(!matches("web", "webserver"));
+        assert!(matches("web*", "webserver"));
+        assert!(matches("web*", "web"));
+        assert!(matches("*-prod", "db-prod"));
+        assert!(!matches("*-prod", "db-production"));
+        assert!(matches("db-?", "db-1"));
+        assert!(!matches("db-?", "db-10"));
+        assert!(matches("*a*b*", "xaxxbx"));
+        assert!(!matches("*a*b*", "xbxxax"));
+        assert!(matches("*", ""));
+    }
+
+    #[test]
+    fn path_wildcards() {
+        assert!(matches_path("/usr/lib64/plugins/*", "/usr/lib64/plugins/check_disk"));
+        assert!(!matches_path("/usr/lib64/plugins/*", "/usr/lib64/plugins/sub/check_disk"));
+        assert!(!matches_path("/usr/lib64/plugins/check_?", "/usr/lib64/plugins/check_/"));
+        assert!(matches_path("/srv/*/data", "/srv/app/data"));
+        assert!(!matches_path("/srv/*", "/srv"));
+        assert!(matches_path("--file=/srv/*", "--file=/srv/x"));
+    }
+}